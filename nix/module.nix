@@ -17,6 +17,7 @@ let
   defaultPackage = pkgs.callPackage ./package.nix { };
 
   runtimeDir = "/run/rest2smtp";
+  stateDir = "/var/lib/rest2smtp";
 
   startScript = pkgs.writeShellScript "rest2smtp-start" ''
     set -euo pipefail
@@ -31,6 +32,28 @@ let
     ${lib.optionalString (cfg.apiTokenFile != null) ''
       export API_TOKEN=$(tr -d '\n\r' < "$CREDENTIALS_DIRECTORY/api.token")
     ''}
+    ${lib.optionalString (cfg.dkim.keys != { }) ''
+      export DKIM_KEYS="${
+        lib.concatStringsSep "," (
+          lib.mapAttrsToList (
+            domain: key: "${domain}:${key.selector}:$CREDENTIALS_DIRECTORY/dkim-${domain}"
+          ) cfg.dkim.keys
+        )
+      }"
+    ''}
+    ${lib.optionalString (cfg.smime.certFile != null) ''
+      export SMIME_CERT_FILE="$CREDENTIALS_DIRECTORY/smime.pem"
+      export SMIME_KEY_FILE="$CREDENTIALS_DIRECTORY/smime.key"
+    ''}
+    ${lib.optionalString (cfg.pgp.signingKeyFile != null) ''
+      export PGP_SIGNING_KEY_FILE="$CREDENTIALS_DIRECTORY/pgp-signing.key"
+    ''}
+    ${lib.optionalString (cfg.pgp.passphraseFile != null) ''
+      export PGP_SIGNING_KEY_PASSPHRASE=$(tr -d '\n\r' < "$CREDENTIALS_DIRECTORY/pgp.pass")
+    ''}
+    ${lib.optionalString (cfg.unsubscribe.secretFile != null) ''
+      export UNSUBSCRIBE_SECRET=$(tr -d '\n\r' < "$CREDENTIALS_DIRECTORY/unsubscribe.secret")
+    ''}
     cd "$runtimeDir"
     exec ${lib.getExe cfg.package}
   '';
@@ -77,7 +100,7 @@ in
       type = lib.types.nullOr lib.types.str;
       default = null;
       description = ''
-        Shared bearer token for `POST /send`.
+        Shared bearer token for the API.
         Prefer {option}`services.rest2smtp.apiTokenFile` for secrets.
         When unset (and no token file), the API stays open.
       '';
@@ -88,12 +111,85 @@ in
       default = null;
       example = "/etc/rest2smtp.token";
       description = ''
-        File containing the shared bearer token for `POST /send`.
+        File containing the shared bearer token for the API.
         Prefer deploying this with NixOps `deployment.keys` to the target path.
         When unset (and no inline token), the API stays open.
       '';
     };
 
+    htmlToText = {
+      enable = lib.mkOption {
+        type = lib.types.bool;
+        default = true;
+        description = "Generate the plain text part of mails that only have HTML content.";
+      };
+
+      width = lib.mkOption {
+        type = lib.types.ints.positive;
+        default = 78;
+        description = "Line width of the generated plain text.";
+      };
+    };
+
+    html = {
+      inlineCss = lib.mkOption {
+        type = lib.types.bool;
+        default = false;
+        description = "Move `<style>` rules of HTML content into `style` attributes by default.";
+      };
+
+      sanitize = lib.mkOption {
+        type = lib.types.bool;
+        default = false;
+        description = "Remove scripts, event handlers and other dangerous markup from HTML content by default.";
+      };
+    };
+
+    idempotencyWindow = lib.mkOption {
+      type = lib.types.ints.positive;
+      default = 86400;
+      description = "Seconds an `Idempotency-Key` of a send request is remembered.";
+    };
+
+    headerDenylist = lib.mkOption {
+      type = lib.types.nullOr (lib.types.listOf lib.types.str);
+      default = null;
+      example = [ "Sender" "Return-Path" "DKIM-Signature" "X-Mailer" ];
+      description = ''
+        Mail headers that requests cannot set via `headers`.
+        When unset, the upstream default is used.
+      '';
+    };
+
+    recipientAllowlist = lib.mkOption {
+      type = lib.types.listOf lib.types.str;
+      default = [ ];
+      example = [ "example.org" "*.example.org" "partner@example.com" ];
+      description = ''
+        Domains, `*` patterns or addresses mails may be sent to.
+        When empty, every recipient not on the denylist is allowed.
+      '';
+    };
+
+    recipientDenylist = lib.mkOption {
+      type = lib.types.listOf lib.types.str;
+      default = [ ];
+      example = [ "customers.example.org" ];
+      description = "Domains, `*` patterns or addresses mails must never be sent to.";
+    };
+
+    templateDir = lib.mkOption {
+      type = lib.types.nullOr lib.types.path;
+      default = null;
+      example = "/etc/rest2smtp/templates";
+      description = ''
+        Directory with mail templates, one sub directory per template
+        containing `subject.txt` and `content.html` and/or `content.txt`.
+        When unset, templates are kept in the state directory so they can be
+        managed through the template API.
+      '';
+    };
+
     environmentFile = lib.mkOption {
       type = lib.types.nullOr lib.types.path;
       default = null;
@@ -105,6 +201,160 @@ in
       '';
     };
 
+    queue = {
+      maxAttempts = lib.mkOption {
+        type = lib.types.ints.positive;
+        default = 10;
+        description = "Delivery attempts before a queued mail is given up.";
+      };
+
+      retryDelay = lib.mkOption {
+        type = lib.types.ints.unsigned;
+        default = 60;
+        description = "Seconds before the first retry; doubled after each transient SMTP failure.";
+      };
+
+      retryMaxDelay = lib.mkOption {
+        type = lib.types.ints.unsigned;
+        default = 3600;
+        description = "Upper bound in seconds for the retry delay.";
+      };
+
+      retention = lib.mkOption {
+        type = lib.types.ints.unsigned;
+        default = 604800;
+        description = "Seconds to keep the status of sent or failed mails queryable.";
+      };
+    };
+
+    dkim = {
+      keys = lib.mkOption {
+        type = lib.types.attrsOf (
+          lib.types.submodule {
+            options = {
+              selector = lib.mkOption {
+                type = lib.types.str;
+                example = "mail";
+                description = "DKIM selector of the key published in DNS.";
+              };
+
+              keyFile = lib.mkOption {
+                type = lib.types.path;
+                example = "/etc/dkim/example.org.pem";
+                description = "PKCS#1 PEM RSA key or base64 encoded Ed25519 key.";
+              };
+            };
+          }
+        );
+        default = { };
+        example = {
+          "example.org" = {
+            selector = "mail";
+            keyFile = "/etc/dkim/example.org.pem";
+          };
+        };
+        description = ''
+          DKIM signing keys by domain. Mails are signed with the key of the
+          From address domain or its closest parent domain.
+          Key files are passed to the service as systemd credentials.
+        '';
+      };
+
+      headers = lib.mkOption {
+        type = lib.types.nullOr (lib.types.listOf lib.types.str);
+        default = null;
+        example = [ "From" "To" "Subject" "Date" "Message-ID" ];
+        description = ''
+          Headers covered by the DKIM signature.
+          When unset, the upstream default is used.
+        '';
+      };
+    };
+
+    smime = {
+      certFile = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        example = "/etc/smime/sender.pem";
+        description = ''
+          PEM file with the S/MIME signing certificate followed by its chain.
+          Enables signing of mails requested with `smime_sign`.
+        '';
+      };
+
+      keyFile = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        example = "/etc/smime/sender.key";
+        description = "PEM private key of the S/MIME signing certificate, passed as systemd credential.";
+      };
+
+      certDir = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        example = "/etc/smime/recipients";
+        description = ''
+          Directory with recipient certificates named `<address>.pem`,
+          used to encrypt mails requested with `smime_encrypt`.
+        '';
+      };
+    };
+
+    pgp = {
+      signingKeyFile = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        example = "/etc/rest2smtp/signing.asc";
+        description = ''
+          OpenPGP secret key used for mails requested with `pgp_sign`,
+          passed to the service as systemd credential.
+        '';
+      };
+
+      passphraseFile = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        example = "/etc/rest2smtp/signing.pass";
+        description = "File containing the passphrase of the OpenPGP signing key.";
+      };
+
+      keyringDir = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        example = "/etc/rest2smtp/keyring";
+        description = ''
+          Directory with OpenPGP public keys of recipients,
+          used to encrypt mails requested with `pgp_encrypt`.
+        '';
+      };
+    };
+
+    unsubscribe = {
+      default = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "mailto:unsubscribe@example.org";
+        description = "Default `List-Unsubscribe` target for mails without `list_unsubscribe`.";
+      };
+
+      url = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "https://mail.example.org";
+        description = ''
+          Public https base URL of rest2smtp, enables the one-click unsubscribe endpoint.
+          Requires {option}`services.rest2smtp.unsubscribe.secretFile`.
+        '';
+      };
+
+      secretFile = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        example = "/etc/rest2smtp/unsubscribe.secret";
+        description = "File containing the key one-click unsubscribe links are signed with.";
+      };
+    };
+
     smtp = {
       host = lib.mkOption {
         type = lib.types.str;
@@ -167,6 +417,14 @@ in
         assertion = !(cfg.apiToken != null && cfg.apiTokenFile != null);
         message = "Set only one of services.rest2smtp.apiToken or services.rest2smtp.apiTokenFile.";
       }
+      {
+        assertion = (cfg.unsubscribe.url == null) == (cfg.unsubscribe.secretFile == null);
+        message = "services.rest2smtp.unsubscribe.url and services.rest2smtp.unsubscribe.secretFile must be set together.";
+      }
+      {
+        assertion = (cfg.smime.certFile == null) == (cfg.smime.keyFile == null);
+        message = "services.rest2smtp.smime.certFile and services.rest2smtp.smime.keyFile must be set together.";
+      }
     ];
 
     systemd.services.rest2smtp = {
@@ -188,14 +446,35 @@ in
         SMTP_ENCRYPTION = cfg.smtp.encryption;
         SMTP_USERNAME = cfg.smtp.username;
         SMTP_PASSWORD = cfg.smtp.password;
+        DKIM_HEADERS = if cfg.dkim.headers != null then lib.concatStringsSep "," cfg.dkim.headers else null;
+        PGP_KEYRING_DIR = if cfg.pgp.keyringDir != null then toString cfg.pgp.keyringDir else null;
+        SMIME_CERT_DIR = if cfg.smime.certDir != null then toString cfg.smime.certDir else null;
         API_TOKEN = cfg.apiToken;
         API_DOC_INFO = cfg.apiDocInfo;
+        TEMPLATE_DIR = if cfg.templateDir != null then toString cfg.templateDir else "${stateDir}/templates";
+        HTML_TO_TEXT = lib.boolToString cfg.htmlToText.enable;
+        HTML_TO_TEXT_WIDTH = toString cfg.htmlToText.width;
+        HTML_INLINE_CSS = lib.boolToString cfg.html.inlineCss;
+        HTML_SANITIZE = lib.boolToString cfg.html.sanitize;
+        LIST_UNSUBSCRIBE = cfg.unsubscribe.default;
+        UNSUBSCRIBE_URL = cfg.unsubscribe.url;
+        UNSUBSCRIBE_FILE = "${stateDir}/unsubscribed.txt";
+        IDEMPOTENCY_WINDOW = toString cfg.idempotencyWindow;
+        HEADER_DENYLIST = if cfg.headerDenylist != null then lib.concatStringsSep "," cfg.headerDenylist else null;
+        RECIPIENT_ALLOWLIST = lib.concatStringsSep "," cfg.recipientAllowlist;
+        RECIPIENT_DENYLIST = lib.concatStringsSep "," cfg.recipientDenylist;
+        QUEUE_DIR = "${stateDir}/queue";
+        QUEUE_MAX_ATTEMPTS = toString cfg.queue.maxAttempts;
+        QUEUE_RETRY_DELAY = toString cfg.queue.retryDelay;
+        QUEUE_RETRY_MAX_DELAY = toString cfg.queue.retryMaxDelay;
+        QUEUE_RETENTION = toString cfg.queue.retention;
       };
 
       serviceConfig = {
         Type = "simple";
         DynamicUser = true;
         RuntimeDirectory = "rest2smtp";
+        StateDirectory = "rest2smtp";
         WorkingDirectory = runtimeDir;
         ExecStart = startScript;
         Restart = "on-failure";
@@ -203,7 +482,15 @@ in
 
         LoadCredential =
           lib.optional (cfg.smtp.passwordFile != null) "smtp.pass:${toString cfg.smtp.passwordFile}"
-          ++ lib.optional (cfg.apiTokenFile != null) "api.token:${toString cfg.apiTokenFile}";
+          ++ lib.optional (cfg.apiTokenFile != null) "api.token:${toString cfg.apiTokenFile}"
+          ++ lib.mapAttrsToList (domain: key: "dkim-${domain}:${toString key.keyFile}") cfg.dkim.keys
+          ++ lib.optionals (cfg.smime.certFile != null) [
+            "smime.pem:${toString cfg.smime.certFile}"
+            "smime.key:${toString cfg.smime.keyFile}"
+          ]
+          ++ lib.optional (cfg.pgp.signingKeyFile != null) "pgp-signing.key:${toString cfg.pgp.signingKeyFile}"
+          ++ lib.optional (cfg.pgp.passphraseFile != null) "pgp.pass:${toString cfg.pgp.passphraseFile}"
+          ++ lib.optional (cfg.unsubscribe.secretFile != null) "unsubscribe.secret:${toString cfg.unsubscribe.secretFile}";
 
         AmbientCapabilities = lib.mkIf (cfg.port < 1024) [ "CAP_NET_BIND_SERVICE" ];
         CapabilityBoundingSet = lib.mkIf (cfg.port < 1024) [ "CAP_NET_BIND_SERVICE" ];
@@ -4,7 +4,7 @@
 }:
 let
   version = "0.0.0";
-  cargoHash = "sha256-HHTOkjsJ46t3BtumtLi2yX1zFpk5G07ualhHeQWzVaU=";
+  cargoHash = "sha256-28AOtzhkQPKiPZSl5KmNcfhnCWx/7js8Ujqm8AOkLVs=";
   swaggerUiRev = "v5.18.2";
   swaggerUiHash = "sha256-JceFGTjNicDUVPanDPk5TUDeG0oFWyzC8SCFXbOPC1o=";
 
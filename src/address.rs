@@ -0,0 +1,136 @@
+use lettre::message::Mailbox;
+use lettre::Address;
+use rocket::serde::{Deserialize, Deserializer};
+
+/// Splits recipient lists like `Jane Doe <jane@example.org>, "Doe, Bob" <bob@example.org>`
+/// at `,` and `;` outside of quotes and angle brackets, dropping comments. RFC 5322 groups
+/// (`Team: a@example.org, b@example.org;`) are flattened into their members.
+pub fn split(values: &[String]) -> Vec<String> {
+    values.iter().flat_map(|value| split_list(value)).collect()
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let (mut quoted, mut escaped, mut angle, mut literal) = (false, false, false, false);
+    let mut comment = 0;
+    for c in list.chars() {
+        // comments are dropped, they carry no meaning for the recipient
+        if escaped {
+            escaped = false;
+            if comment == 0 {
+                current.push(c);
+            }
+            continue;
+        }
+        match c {
+            '\\' if quoted || comment > 0 => escaped = true,
+            '(' if !quoted => comment += 1,
+            ')' if !quoted && comment > 0 => {
+                comment -= 1;
+                continue;
+            }
+            _ if comment > 0 => {}
+            '"' => quoted = !quoted,
+            _ if quoted => {}
+            '<' => angle = true,
+            '>' => angle = false,
+            '[' => literal = true,
+            ']' => literal = false,
+            // the display name of a group
+            ':' if !angle && !literal => {
+                current.clear();
+                continue;
+            }
+            ',' | ';' if !angle && !literal => {
+                items.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        if comment == 0 {
+            current.push(c);
+        }
+    }
+    items.push(current);
+    items
+        .into_iter()
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Parses a single `name <address>` or bare address. lettre ignores trailing input, so
+/// the address in angle brackets is checked on its own as well.
+pub fn parse_mailbox(value: &str) -> Option<Mailbox> {
+    let value = value.trim();
+    if !value.ends_with('>') {
+        return value.parse::<Address>().ok().map(Mailbox::from);
+    }
+    let mailbox = value.parse::<Mailbox>().ok()?;
+    let address = value[value.rfind('<')? + 1..value.len() - 1].trim();
+    (address.parse::<Address>().ok()? == mailbox.email).then_some(mailbox)
+}
+
+/// All mailboxes of the given recipient lists, errors name the first invalid entry.
+pub fn parse_list(values: &[String]) -> Result<Vec<Mailbox>, String> {
+    split(values)
+        .iter()
+        .map(|value| parse_mailbox(value).ok_or_else(|| format!("invalid address \"{}\"", value)))
+        .collect()
+}
+
+/// Deserializes a single string as well as an array of strings.
+pub fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(crate = "rocket::serde", untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_lists_and_groups() {
+        assert_eq!(
+            split(&[
+                r#"Jane Doe <jane@example.org>; "Doe, Bob" <bob@example.org>"#.into(),
+                "Team: a@example.org, b@example.org (Bea; B.);, c@[IPv6:::1]".into(),
+                "undisclosed-recipients:;".into(),
+            ]),
+            [
+                "Jane Doe <jane@example.org>",
+                r#""Doe, Bob" <bob@example.org>"#,
+                "a@example.org",
+                "b@example.org",
+                "c@[IPv6:::1]",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_mailboxes_strictly() {
+        let jane = parse_mailbox(r#""Doe, Jane" <jane@example.org>"#).unwrap();
+        assert_eq!(jane.name.as_deref(), Some("Doe, Jane"));
+        assert_eq!(jane.email.to_string(), "jane@example.org");
+        assert_eq!(
+            parse_mailbox("jane@example.org").unwrap().name.as_deref(),
+            None
+        );
+        assert!(parse_mailbox("jane@example.org trailing").is_none());
+        assert!(parse_mailbox("Jane <jane@example.org trailing>").is_none());
+        assert!(parse_mailbox("ab").is_none());
+        assert_eq!(
+            parse_list(&["a@example.org, ab".into()]).unwrap_err(),
+            "invalid address \"ab\""
+        );
+    }
+}
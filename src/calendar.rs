@@ -0,0 +1,313 @@
+use lettre::message::{header::ContentType, Mailbox, SinglePart};
+use rocket::serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
+
+/// Calendar invitation, sent as `text/calendar` part (iTIP, RFC 5546) next to the text
+/// and HTML parts. `start` and `end` are RFC 3339 timestamps, written in UTC so no
+/// VTIMEZONE with the DST rules of a zone is needed; calendar clients show them in the
+/// local time of the reader. A `timezone` is refused rather than ignored. Every field is
+/// optional here so the form input can tell a missing event from an invalid one.
+#[derive(Deserialize, Serialize, FromForm, Clone, Debug, Default, Hash)]
+#[serde(crate = "rocket::serde")]
+pub struct Event {
+    /// stays the same for all updates and the cancellation of an event
+    pub uid: Option<String>,
+    /// `REQUEST` (default) or `CANCEL`
+    pub method: Option<String>,
+    /// revision of the event, has to increase with every update
+    pub sequence: Option<u32>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    /// not supported, only kept to refuse it
+    pub timezone: Option<String>,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    /// defaults to the sender
+    pub organizer: Option<String>,
+    #[serde(default)]
+    pub attendees: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Method {
+    Request,
+    Cancel,
+}
+
+impl Method {
+    fn as_str(self) -> &'static str {
+        match self {
+            Method::Request => "REQUEST",
+            Method::Cancel => "CANCEL",
+        }
+    }
+}
+
+impl Event {
+    /// True if none of the fields were given, e.g. a form without `event.*` fields.
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none()
+            && self.method.is_none()
+            && self.sequence.is_none()
+            && self.start.is_none()
+            && self.end.is_none()
+            && self.timezone.is_none()
+            && self.summary.is_none()
+            && self.description.is_none()
+            && self.location.is_none()
+            && self.organizer.is_none()
+            && self.attendees.is_empty()
+    }
+
+    /// The `text/calendar` part, `sender` is the organizer unless one is given.
+    pub fn part(&self, sender: &Mailbox) -> Result<SinglePart, String> {
+        let (method, ics) = self.render(sender, OffsetDateTime::now_utc())?;
+        let content_type = ContentType::parse(&format!(
+            "text/calendar; method={}; charset=utf-8",
+            method.as_str()
+        ))
+        .map_err(|e| e.to_string())?;
+        Ok(SinglePart::builder().header(content_type).body(ics))
+    }
+
+    fn render(&self, sender: &Mailbox, now: OffsetDateTime) -> Result<(Method, String), String> {
+        let method = match self.method.as_deref().map(str::trim) {
+            None => Method::Request,
+            Some(method) if method.eq_ignore_ascii_case("REQUEST") => Method::Request,
+            Some(method) if method.eq_ignore_ascii_case("CANCEL") => Method::Cancel,
+            Some(method) => return Err(format!("event.method \"{}\" is not supported", method)),
+        };
+        if self.timezone.is_some() {
+            return Err("event.timezone is not supported, start and end are written in UTC".into());
+        }
+        let uid = required("uid", &self.uid)?;
+        let summary = match method {
+            Method::Request => Some(required("summary", &self.summary)?),
+            Method::Cancel => self.summary.as_deref(),
+        };
+        let start = timestamp("start", &self.start)?;
+        let end = timestamp("end", &self.end)?;
+        if end <= start {
+            return Err("event.end must be after event.start".into());
+        }
+        let organizer = match &self.organizer {
+            Some(organizer) => mailbox("organizer", organizer)?,
+            None => sender.clone(),
+        };
+        if self.attendees.is_empty() {
+            return Err("event.attendees missing or empty".into());
+        }
+        let attendees = self
+            .attendees
+            .iter()
+            .map(|attendee| mailbox("attendees", attendee))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut lines = vec![
+            "BEGIN:VCALENDAR".to_string(),
+            "PRODID:-//rest2smtp//EN".into(),
+            "VERSION:2.0".into(),
+            "CALSCALE:GREGORIAN".into(),
+            format!("METHOD:{}", method.as_str()),
+            "BEGIN:VEVENT".into(),
+            format!("UID:{}", escape(uid)),
+            format!("SEQUENCE:{}", self.sequence.unwrap_or(0)),
+            format!("DTSTAMP:{}", utc_time(now)),
+            format!("DTSTART:{}", utc_time(start)),
+            format!("DTEND:{}", utc_time(end)),
+        ];
+        if let Some(summary) = summary {
+            lines.push(format!("SUMMARY:{}", escape(summary)));
+        }
+        if let Some(description) = &self.description {
+            lines.push(format!("DESCRIPTION:{}", escape(description)));
+        }
+        if let Some(location) = &self.location {
+            lines.push(format!("LOCATION:{}", escape(location)));
+        }
+        lines.push(format!("ORGANIZER{}", address(&organizer)));
+        for attendee in &attendees {
+            lines.push(match method {
+                Method::Request => format!(
+                    "ATTENDEE;ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=TRUE{}",
+                    address(attendee)
+                ),
+                Method::Cancel => format!("ATTENDEE;ROLE=REQ-PARTICIPANT{}", address(attendee)),
+            });
+        }
+        lines.extend([
+            match method {
+                Method::Request => "STATUS:CONFIRMED".to_string(),
+                Method::Cancel => "STATUS:CANCELLED".into(),
+            },
+            "END:VEVENT".into(),
+            "END:VCALENDAR".into(),
+        ]);
+
+        Ok((method, lines.iter().map(|line| fold(line)).collect()))
+    }
+}
+
+fn required<'a>(name: &str, value: &'a Option<String>) -> Result<&'a str, String> {
+    match value.as_deref().map(str::trim) {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(format!("event.{} missing or empty", name)),
+    }
+}
+
+fn timestamp(name: &str, value: &Option<String>) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(required(name, value)?, &Rfc3339)
+        .map_err(|e| format!("event.{} is not an RFC 3339 timestamp: {}", name, e))
+}
+
+fn mailbox(name: &str, value: &str) -> Result<Mailbox, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("event.{} contains invalid address", name))
+}
+
+// `;CN="Name":mailto:address`, quotes are not allowed inside parameter values
+fn address(mailbox: &Mailbox) -> String {
+    match &mailbox.name {
+        Some(name) => format!(
+            ";CN=\"{}\":mailto:{}",
+            name.chars()
+                .filter(|c| *c != '"' && !c.is_control())
+                .collect::<String>(),
+            mailbox.email
+        ),
+        None => format!(":mailto:{}", mailbox.email),
+    }
+}
+
+fn utc_time(time: OffsetDateTime) -> String {
+    let time = time.to_offset(UtcOffset::UTC);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+// TEXT values, RFC 5545 section 3.3.11
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c if c.is_control() && c != '\t' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// content lines are folded after 75 octets, continuation lines start with a space
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 8);
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender() -> Mailbox {
+        "Ops <ops@example.org>".parse().unwrap()
+    }
+
+    fn now() -> OffsetDateTime {
+        OffsetDateTime::parse("2026-10-01T12:00:00Z", &Rfc3339).unwrap()
+    }
+
+    #[test]
+    fn renders_request_in_utc() {
+        // ends after the switch from CEST to CET
+        let event = Event {
+            uid: Some("maintenance-42@example.org".into()),
+            start: Some("2026-10-25T01:30:00+02:00".into()),
+            end: Some("2026-10-25T02:30:00+01:00".into()),
+            summary: Some("Maintenance; database, cache".into()),
+            description: Some(format!("Line one\n{}", "x".repeat(80))),
+            location: Some("Data center".into()),
+            attendees: vec![
+                "Jane Doe <jane@example.org>".into(),
+                "bob@example.org".into(),
+            ],
+            ..Default::default()
+        };
+        let (method, ics) = event.render(&sender(), now()).unwrap();
+        assert_eq!(method, Method::Request);
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert!(ics.contains("\r\nMETHOD:REQUEST\r\n"));
+        assert!(ics.contains("\r\nDTSTART:20261024T233000Z\r\n"));
+        assert!(ics.contains("\r\nDTEND:20261025T013000Z\r\n"));
+        assert!(!ics.contains("VTIMEZONE"));
+        assert!(ics.contains("\r\nDTSTAMP:20261001T120000Z\r\n"));
+        assert!(ics.contains("\r\nSUMMARY:Maintenance\\; database\\, cache\r\n"));
+        assert!(ics.contains("\r\nORGANIZER;CN=\"Ops\":mailto:ops@example.org\r\n"));
+        let unfolded = ics.replace("\r\n ", "");
+        assert!(unfolded.contains(
+            "\r\nATTENDEE;ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=TRUE;CN=\"Jane Doe\":mailto:jane@example.org\r\n"
+        ));
+        assert!(ics.contains("\r\nSTATUS:CONFIRMED\r\n"));
+        assert!(unfolded.contains(&format!("DESCRIPTION:Line one\\n{}\r\n", "x".repeat(80))));
+        assert!(ics.split("\r\n").all(|line| line.len() <= 75));
+    }
+
+    #[test]
+    fn renders_cancel_and_validates() {
+        let mut event = Event {
+            uid: Some("maintenance-42@example.org".into()),
+            method: Some("cancel".into()),
+            sequence: Some(1),
+            start: Some("2026-10-20T22:00:00+02:00".into()),
+            end: Some("2026-10-20T23:30:00+02:00".into()),
+            organizer: Some("noc@example.org".into()),
+            attendees: vec!["jane@example.org".into()],
+            ..Default::default()
+        };
+        let (method, ics) = event.render(&sender(), now()).unwrap();
+        assert_eq!(method, Method::Cancel);
+        assert!(ics.contains("\r\nMETHOD:CANCEL\r\n"));
+        assert!(ics.contains("\r\nSEQUENCE:1\r\n"));
+        assert!(ics.contains("\r\nDTSTART:20261020T200000Z\r\n"));
+        assert!(ics.contains("\r\nORGANIZER:mailto:noc@example.org\r\n"));
+        assert!(ics.contains("\r\nSTATUS:CANCELLED\r\n"));
+
+        event.end = Some("2026-10-20T21:00:00+02:00".into());
+        assert!(event.render(&sender(), now()).is_err());
+        event.end = Some("2026-10-20T23:30:00+02:00".into());
+        event.method = Some("PUBLISH".into());
+        assert!(event.render(&sender(), now()).is_err());
+        event.method = None;
+        event.timezone = Some("Europe/Berlin".into());
+        assert_eq!(
+            event.render(&sender(), now()).unwrap_err(),
+            "event.timezone is not supported, start and end are written in UTC"
+        );
+        assert!(Event::default().is_empty());
+        assert!(!event.is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use std::env;
 use std::fmt;
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum SmtpEncryption {
@@ -18,6 +19,17 @@ impl fmt::Display for SmtpEncryption {
     }
 }
 
+/// DKIM key used for mails from `domain` and its subdomains.
+#[derive(Debug, PartialEq)]
+pub struct DkimDomain {
+    pub domain: String,
+    pub selector: String,
+    pub private_key_file: PathBuf,
+}
+
+/// Headers signed when `DKIM_HEADERS` is not set.
+const DEFAULT_DKIM_HEADERS: &str = "From,Sender,Reply-To,Subject,Date,Message-ID,To,Cc,In-Reply-To,References,MIME-Version,Content-Type,Content-Transfer-Encoding";
+
 #[derive(Debug)]
 pub struct SmtpConfig {
     pub host: String,
@@ -25,6 +37,8 @@ pub struct SmtpConfig {
     pub encryption: SmtpEncryption,
     pub username: Option<String>,
     pub password: Option<String>,
+    pub dkim: Vec<DkimDomain>,
+    pub dkim_headers: Vec<String>,
 }
 
 impl SmtpConfig {
@@ -52,6 +66,84 @@ impl SmtpConfig {
                 },
                 None => SmtpEncryption::Tls,
             },
+            dkim: dkim_domains(),
+            dkim_headers: env::var("DKIM_HEADERS")
+                .unwrap_or(DEFAULT_DKIM_HEADERS.to_string())
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect(),
         }
     }
 }
+
+// a single key via DKIM_DOMAIN/DKIM_SELECTOR/DKIM_PRIVATE_KEY_FILE plus any number of
+// "domain:selector:key_file" entries in DKIM_KEYS
+fn dkim_domains() -> Vec<DkimDomain> {
+    let mut domains = Vec::new();
+    if let Ok(domain) = env::var("DKIM_DOMAIN") {
+        domains.push(DkimDomain {
+            domain: domain.trim().to_lowercase(),
+            selector: env::var("DKIM_SELECTOR").expect("DKIM_SELECTOR is not set"),
+            private_key_file: env::var("DKIM_PRIVATE_KEY_FILE")
+                .expect("DKIM_PRIVATE_KEY_FILE is not set")
+                .into(),
+        });
+    }
+    if let Ok(keys) = env::var("DKIM_KEYS") {
+        domains.extend(parse_dkim_keys(&keys));
+    }
+    domains
+}
+
+fn parse_dkim_keys(keys: &str) -> Vec<DkimDomain> {
+    keys.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.splitn(3, ':').collect::<Vec<_>>()[..] {
+            [domain, selector, private_key_file]
+                if !domain.trim().is_empty() && !selector.trim().is_empty() =>
+            {
+                DkimDomain {
+                    domain: domain.trim().to_lowercase(),
+                    selector: selector.trim().to_string(),
+                    private_key_file: private_key_file.trim().into(),
+                }
+            }
+            _ => panic!(
+                "DKIM_KEYS entry \"{}\" is not in the form domain:selector:key_file",
+                entry
+            ),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dkim_keys() {
+        assert_eq!(
+            parse_dkim_keys("Example.org:mail:/keys/a.pem, example.com:s2:/keys/b.key,"),
+            [
+                DkimDomain {
+                    domain: "example.org".into(),
+                    selector: "mail".into(),
+                    private_key_file: "/keys/a.pem".into(),
+                },
+                DkimDomain {
+                    domain: "example.com".into(),
+                    selector: "s2".into(),
+                    private_key_file: "/keys/b.key".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_incomplete_dkim_keys() {
+        parse_dkim_keys("example.org:/keys/a.pem");
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use uuid::Uuid;
+
+/// Image taken out of a `data:` URI, to be attached as inline part.
+#[derive(Debug, PartialEq)]
+pub struct InlineImage {
+    pub content_id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+/// Replaces base64 `data:image/...` URIs in `src` attributes with `cid:` references and
+/// returns the rewritten HTML together with the extracted images. Identical URIs share
+/// one image, anything that cannot be decoded is left untouched.
+pub fn extract_images(html: &str) -> (String, Vec<InlineImage>) {
+    // ASCII lowercasing keeps byte offsets, so positions found in `lower` apply to `html`
+    let lower = html.to_ascii_lowercase();
+    let mut rewritten = String::with_capacity(html.len());
+    let mut images: Vec<InlineImage> = Vec::new();
+    let mut content_ids: HashMap<&str, String> = HashMap::new();
+    let mut copied = 0;
+    let mut search = 0;
+
+    while let Some(found) = lower[search..].find("src") {
+        let attr_start = search + found;
+        search = attr_start + 3;
+        // skip names that merely end with "src", e.g. data-src
+        if attr_start > 0 && !lower.as_bytes()[attr_start - 1].is_ascii_whitespace() {
+            continue;
+        }
+        let Some((value_start, value_end)) = attribute_value(&lower, search) else {
+            continue;
+        };
+        search = value_end;
+
+        let value = &html[value_start..value_end];
+        let content_id = match content_ids.get(value) {
+            Some(content_id) => content_id.clone(),
+            None => {
+                let Some((content_type, body)) = decode(value) else {
+                    continue;
+                };
+                let content_id = format!("{}@rest2smtp", Uuid::new_v4().simple());
+                let extension = content_type
+                    .trim_start_matches("image/")
+                    .split('+')
+                    .next()
+                    .unwrap_or("bin");
+                images.push(InlineImage {
+                    content_id: content_id.clone(),
+                    filename: format!("image{}.{}", images.len() + 1, extension),
+                    content_type,
+                    body,
+                });
+                content_ids.insert(value, content_id.clone());
+                content_id
+            }
+        };
+
+        rewritten.push_str(&html[copied..value_start]);
+        rewritten.push_str("cid:");
+        rewritten.push_str(&content_id);
+        copied = value_end;
+    }
+    rewritten.push_str(&html[copied..]);
+    (rewritten, images)
+}
+
+/// Finds the quoted value of an attribute whose name ends right before `pos`.
+fn attribute_value(html: &str, pos: usize) -> Option<(usize, usize)> {
+    let rest = &html[pos..];
+    let after_name = rest.trim_start();
+    let after_eq = after_name.strip_prefix('=')?.trim_start();
+    let quote = after_eq
+        .chars()
+        .next()
+        .filter(|c| *c == '"' || *c == '\'')?;
+    let value_start = pos + (rest.len() - after_eq.len()) + 1;
+    let value_len = html[value_start..].find(quote)?;
+    Some((value_start, value_start + value_len))
+}
+
+/// Decodes `data:image/<type>[;params];base64,<data>` into content type and bytes.
+fn decode(uri: &str) -> Option<(String, Vec<u8>)> {
+    let uri = uri.trim();
+    if !uri.get(..5)?.eq_ignore_ascii_case("data:") {
+        return None;
+    }
+    let (meta, data) = uri[5..].split_once(',')?;
+    let meta = meta.to_ascii_lowercase();
+    let media_type = meta.strip_suffix(";base64")?;
+    let content_type = media_type.split(';').next()?.trim();
+    if !content_type.starts_with("image/") {
+        return None;
+    }
+    let data: String = data.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    let body = BASE64_STANDARD.decode(data).ok()?;
+    Some((content_type.to_string(), body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_data_uris_with_content_ids() {
+        let html = r#"<img alt="a" SRC="data:image/png;base64,iVBO Rw=="><img src='data:image/png;base64,iVBORw=='>"#;
+        let (rewritten, images) = extract_images(html);
+
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].content_type, "image/png");
+        assert_eq!(images[0].filename, "image1.png");
+        assert_eq!(images[0].body, [0x89, b'P', b'N', b'G']);
+        assert_eq!(
+            rewritten,
+            format!(
+                r#"<img alt="a" SRC="cid:{}"><img src='cid:{}'>"#,
+                images[0].content_id, images[1].content_id
+            )
+        );
+    }
+
+    #[test]
+    fn reuses_images_for_identical_uris() {
+        let uri = "data:image/svg+xml;base64,PHN2Zy8+";
+        let (rewritten, images) =
+            extract_images(&format!(r#"<img src="{uri}"><img src = "{uri}">"#));
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].filename, "image1.svg");
+        assert_eq!(rewritten.matches(&images[0].content_id).count(), 2);
+    }
+
+    #[test]
+    fn leaves_other_sources_untouched() {
+        let html = concat!(
+            r#"<img src="https://example.org/a.png">"#,
+            r#"<img data-src="data:image/png;base64,iVBORw==">"#,
+            r#"<img src="data:image/png,raw">"#,
+            r#"<img src="data:text/html;base64,PGI+">"#,
+            r#"<img src="data:image/png;base64,%%%">"#,
+            "src without value",
+        );
+        assert_eq!(extract_images(html), (html.to_string(), Vec::new()));
+    }
+}
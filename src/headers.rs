@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::env;
+
+use lettre::message::header::{HeaderName, HeaderValue};
+
+/// Headers built from dedicated request fields or by the MIME encoder. They can never be
+/// set through the custom `headers` map.
+const RESERVED_HEADERS: &[&str] = &[
+    "From",
+    "To",
+    "Cc",
+    "Bcc",
+    "Subject",
+    "Reply-To",
+    "List-Unsubscribe",
+    "List-Unsubscribe-Post",
+    "Date",
+    "MIME-Version",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+    "Content-Disposition",
+];
+
+/// Default for `HEADER_DENYLIST`: headers that affect sender authentication or tracing.
+const DEFAULT_DENYLIST: &str = "Sender,Return-Path,DKIM-Signature,ARC-Seal,ARC-Message-Signature,ARC-Authentication-Results,Authentication-Results,Received";
+
+/// Custom header rules. Names in `HEADER_DENYLIST` (comma separated, case-insensitive)
+/// are rejected in addition to the reserved headers.
+#[derive(Debug, Clone)]
+pub struct HeaderPolicy {
+    denylist: Vec<String>,
+}
+
+impl HeaderPolicy {
+    pub fn from_env() -> Self {
+        let denylist = env::var("HEADER_DENYLIST").unwrap_or(DEFAULT_DENYLIST.to_string());
+        Self::new(&denylist)
+    }
+
+    fn new(denylist: &str) -> Self {
+        Self {
+            denylist: denylist
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn denylist(&self) -> &[String] {
+        &self.denylist
+    }
+
+    /// Whether `name` is on the denylist.
+    pub fn is_denied(&self, name: &str) -> bool {
+        self.denylist
+            .iter()
+            .any(|denied| denied.eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// Validates the requested headers and turns them into raw header values.
+    pub fn check(&self, headers: &HashMap<String, String>) -> Result<Vec<HeaderValue>, String> {
+        let mut entries: Vec<(&String, &String)> = headers.iter().collect();
+        entries.sort();
+        entries
+            .into_iter()
+            .map(|(name, value)| {
+                let name = name.trim();
+                if RESERVED_HEADERS
+                    .iter()
+                    .any(|reserved| reserved.eq_ignore_ascii_case(name))
+                {
+                    return Err(format!("header \"{}\" cannot be set via headers", name));
+                }
+                if self.is_denied(name) {
+                    return Err(format!("header \"{}\" is not allowed", name));
+                }
+                // line breaks would allow injecting further headers
+                if value.contains(['\r', '\n']) {
+                    return Err(format!("header \"{}\" contains a line break", name));
+                }
+                if !name.chars().all(|c| c.is_ascii_graphic() && c != ':') {
+                    return Err(format!("invalid header name \"{}\"", name));
+                }
+                let header_name = HeaderName::new_from_ascii(name.to_string())
+                    .map_err(|_| format!("invalid header name \"{}\"", name))?;
+                Ok(HeaderValue::new(header_name, value.trim().to_string()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::message::header::Headers;
+
+    fn headers(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn accepts_custom_headers() {
+        let policy = HeaderPolicy::new(DEFAULT_DENYLIST);
+        let checked = policy
+            .check(&headers(&[
+                ("X-Ticket-Id", "42"),
+                ("In-Reply-To", "<abc@example.org>"),
+            ]))
+            .unwrap();
+        assert_eq!(checked.len(), 2);
+        let mut message_headers = Headers::new();
+        checked
+            .into_iter()
+            .for_each(|h| message_headers.insert_raw(h));
+        assert_eq!(message_headers.get_raw("x-ticket-id"), Some("42"));
+    }
+
+    #[test]
+    fn rejects_reserved_denied_and_malformed_headers() {
+        let policy = HeaderPolicy::new("X-Internal, dkim-signature");
+        for (name, value) in [
+            ("from", "evil@example.org"),
+            ("Content-Type", "text/plain"),
+            ("x-internal", "1"),
+            ("DKIM-Signature", "v=1"),
+            ("X-Test", "a\r\nBcc: evil@example.org"),
+            ("X Test", "1"),
+            ("X:Test", "1"),
+        ] {
+            assert!(
+                policy.check(&headers(&[(name, value)])).is_err(),
+                "{} accepted",
+                name
+            );
+        }
+        assert!(policy
+            .check(&headers(&[("Return-Path", "<a@b.c>")]))
+            .is_ok());
+    }
+}
@@ -0,0 +1,164 @@
+use std::borrow::Cow;
+use std::env;
+
+use css_inline::CSSInliner;
+use pulldown_cmark::{Options, Parser};
+
+/// Processing of `content_html`: `HTML_INLINE_CSS` moves `<style>` rules into `style`
+/// attributes, `HTML_SANITIZE` removes scripts, event handlers and other dangerous markup.
+/// Both are off by default and can be switched per mail.
+pub struct HtmlProcessing {
+    pub inline_css: bool,
+    pub sanitize: bool,
+    cleaner: ammonia::Builder<'static>,
+}
+
+impl HtmlProcessing {
+    pub fn from_env() -> Self {
+        Self::new(bool_var("HTML_INLINE_CSS"), bool_var("HTML_SANITIZE"))
+    }
+
+    fn new(inline_css: bool, sanitize: bool) -> Self {
+        Self {
+            inline_css,
+            sanitize,
+            cleaner: cleaner(),
+        }
+    }
+
+    /// Applies the requested steps, falling back to the configured defaults. CSS is
+    /// inlined first, since sanitizing drops `<style>` blocks.
+    pub fn process(
+        &self,
+        html: String,
+        inline_css: Option<bool>,
+        sanitize: Option<bool>,
+    ) -> Result<String, String> {
+        let html = match inline_css.unwrap_or(self.inline_css) {
+            true => inline(&html)?,
+            false => html,
+        };
+        Ok(match sanitize.unwrap_or(self.sanitize) {
+            true => self.clean(&html),
+            false => html,
+        })
+    }
+
+    /// Removes everything but formatting markup from `html`.
+    pub fn clean(&self, html: &str) -> String {
+        self.cleaner.clean(html).to_string()
+    }
+
+    /// Renders CommonMark with tables and strikethrough to HTML. Raw HTML in the source
+    /// is allowed, so the result is always sanitized.
+    pub fn markdown(&self, source: &str) -> String {
+        let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, Parser::new_ext(source, options));
+        self.clean(&html)
+    }
+}
+
+fn bool_var(name: &str) -> bool {
+    match env::var(name).ok().as_deref().map(str::trim) {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => panic!("{} must be true or false", name),
+    }
+}
+
+// remote stylesheets are never fetched, <link> tags are dropped
+fn inline(html: &str) -> Result<String, String> {
+    CSSInliner::options()
+        .load_remote_stylesheets(false)
+        .keep_link_tags(false)
+        .build()
+        .inline(html)
+        .map_err(|e| format!("cannot inline CSS of content_html: {}", e))
+}
+
+// ammonia defaults plus the presentational attributes mail layouts are built with
+fn cleaner() -> ammonia::Builder<'static> {
+    let mut cleaner = ammonia::Builder::default();
+    cleaner
+        .add_tags(["font"])
+        .add_generic_attributes(["style", "align", "valign", "width", "height", "bgcolor"])
+        .add_tag_attributes("table", ["border", "cellpadding", "cellspacing"])
+        .add_tag_attributes("font", ["color", "face", "size"])
+        .add_url_schemes(["cid", "data"])
+        .attribute_filter(|element, attribute, value| {
+            let lower = value.to_ascii_lowercase();
+            match attribute {
+                // data URIs only for images, inline_data_uris turns them into attachments
+                _ if lower.trim_start().starts_with("data:") => (element == "img"
+                    && attribute == "src"
+                    && lower.trim_start().starts_with("data:image/"))
+                .then_some(Cow::Borrowed(value)),
+                "style" if lower.contains("expression(") || lower.contains("javascript:") => None,
+                _ => Some(Cow::Borrowed(value)),
+            }
+        });
+    cleaner
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inlines_css_and_removes_dangerous_markup() {
+        let processing = HtmlProcessing::new(false, false);
+        let html = r#"<html><head><style>p { color: red } .big { font-size: 20px }</style>
+            <script>alert(1)</script></head>
+            <body><p class="big" onclick="steal()">Hi</p>
+            <a href="javascript:alert(1)">x</a><img src="cid:logo.png">
+            <img src="data:image/png;base64,AAAA"><a href="data:text/html;base64,AAAA">y</a>
+            <table width="100%" cellpadding="0"><tr><td valign="top">z</td></tr></table></body></html>"#;
+        let processed = processing
+            .process(html.into(), Some(true), Some(true))
+            .unwrap();
+        assert!(processed.contains(r#"<p style="color: red;font-size: 20px;">Hi</p>"#));
+        assert!(!processed.contains("script"));
+        assert!(!processed.contains("alert"));
+        assert!(!processed.contains("onclick"));
+        assert!(!processed.contains("<style"));
+        assert!(processed.contains(r#"src="cid:logo.png""#));
+        assert!(processed.contains(r#"src="data:image/png;base64,AAAA""#));
+        assert!(!processed.contains("data:text/html"));
+        assert!(processed.contains(r#"<table width="100%" cellpadding="0">"#));
+        assert!(processed.contains(r#"<td valign="top">"#));
+    }
+
+    #[test]
+    fn renders_sanitized_markdown() {
+        let processing = HtmlProcessing::new(false, false);
+        let html = processing.markdown(
+            "# Report\n\n**Done** ~~late~~ [link](https://example.org)\n\n\
+             | a | b |\n|---|---|\n| 1 | 2 |\n\n<script>alert(1)</script>\n",
+        );
+        assert!(html.contains("<h1>Report</h1>"));
+        assert!(html.contains("<strong>Done</strong> <del>late</del>"));
+        assert!(
+            html.contains(r#"<a href="https://example.org" rel="noopener noreferrer">link</a>"#)
+        );
+        assert!(html.contains("<td>1</td>"));
+        assert!(!html.contains("script"));
+    }
+
+    #[test]
+    fn uses_configured_defaults() {
+        let html = "<style>p { color: red }</style><p onclick=\"x()\">Hi</p>";
+        let processing = HtmlProcessing::new(false, false);
+        assert_eq!(processing.process(html.into(), None, None).unwrap(), html);
+
+        let processing = HtmlProcessing::new(false, true);
+        assert_eq!(
+            processing.process(html.into(), None, None).unwrap(),
+            "<p>Hi</p>"
+        );
+        assert_eq!(
+            processing.process(html.into(), None, Some(false)).unwrap(),
+            html
+        );
+    }
+}
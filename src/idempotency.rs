@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::problem::Problem;
+
+/// Longest accepted `Idempotency-Key` header value.
+pub const MAX_KEY_LENGTH: usize = 255;
+
+/// Value of the optional `Idempotency-Key` request header.
+pub struct IdempotencyKey(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let key = req
+            .headers()
+            .get_one("Idempotency-Key")
+            .map(|key| key.trim().to_string());
+        Outcome::Success(IdempotencyKey(key))
+    }
+}
+
+/// Response of a send request, as stored for replays.
+pub type Response = Result<(Status, String), Problem>;
+
+/// Outcome of [`Idempotency::claim`].
+#[derive(Debug, PartialEq)]
+pub enum Claim {
+    /// First use of the key, the request has to be processed.
+    New,
+    /// The key was used before with the same payload, answer with the stored response.
+    Replay(Response),
+    /// The key was used with a different payload.
+    Mismatch,
+    /// A request with the same key is still being processed.
+    InProgress,
+}
+
+struct Entry {
+    fingerprint: u64,
+    claimed_at: Instant,
+    response: Option<Response>,
+}
+
+/// Responses of recent requests by idempotency key, kept in memory for
+/// `IDEMPOTENCY_WINDOW` seconds (default one day).
+pub struct Idempotency {
+    pub window: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl Idempotency {
+    pub fn from_env() -> Self {
+        let window = env::var("IDEMPOTENCY_WINDOW").ok().map(|v| {
+            v.trim()
+                .parse()
+                .unwrap_or_else(|_| panic!("IDEMPOTENCY_WINDOW is not a number"))
+        });
+        Self::new(Duration::from_secs(window.unwrap_or(24 * 3600)))
+    }
+
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserves `key` for a request with the given payload fingerprint.
+    pub fn claim(&self, key: &str, fingerprint: u64) -> Claim {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.claimed_at.elapsed() < self.window);
+
+        match entries.get(key) {
+            Some(entry) if entry.fingerprint != fingerprint => Claim::Mismatch,
+            Some(Entry {
+                response: Some(response),
+                ..
+            }) => Claim::Replay(response.clone()),
+            Some(_) => Claim::InProgress,
+            None => {
+                entries.insert(
+                    key.to_string(),
+                    Entry {
+                        fingerprint,
+                        claimed_at: Instant::now(),
+                        response: None,
+                    },
+                );
+                Claim::New
+            }
+        }
+    }
+
+    /// Stores the response for a claimed key. Server errors release the key instead,
+    /// so the client can retry.
+    pub fn complete(&self, key: &str, response: &Response) {
+        let mut entries = self.entries.lock().unwrap();
+        let status = match response {
+            Ok((status, _)) => *status,
+            Err(problem) => Status::new(problem.status),
+        };
+        if status.class().is_server_error() {
+            entries.remove(key);
+        } else if let Some(entry) = entries.get_mut(key) {
+            entry.response = Some(response.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_responses_for_the_same_payload() {
+        let idempotency = Idempotency::new(Duration::from_secs(60));
+        assert_eq!(idempotency.claim("a", 1), Claim::New);
+        assert_eq!(idempotency.claim("a", 1), Claim::InProgress);
+        idempotency.complete("a", &Ok((Status::Accepted, "id".into())));
+        assert_eq!(
+            idempotency.claim("a", 1),
+            Claim::Replay(Ok((Status::Accepted, "id".into())))
+        );
+        assert_eq!(idempotency.claim("a", 2), Claim::Mismatch);
+        assert_eq!(idempotency.claim("b", 2), Claim::New);
+    }
+
+    #[test]
+    fn server_errors_and_expired_keys_are_released() {
+        let idempotency = Idempotency::new(Duration::from_secs(60));
+        assert_eq!(idempotency.claim("a", 1), Claim::New);
+        idempotency.complete(
+            "a",
+            &Err((Status::InternalServerError, "disk full".to_string()).into()),
+        );
+        assert_eq!(idempotency.claim("a", 2), Claim::New);
+
+        let idempotency = Idempotency::new(Duration::ZERO);
+        assert_eq!(idempotency.claim("a", 1), Claim::New);
+        assert_eq!(idempotency.claim("a", 2), Claim::New);
+    }
+}
@@ -1,11 +1,21 @@
+use std::fs;
+
+use lettre::message::dkim::{
+    dkim_sign, DkimCanonicalization, DkimCanonicalizationType, DkimConfig, DkimSigningAlgorithm,
+    DkimSigningKey,
+};
+use lettre::message::header::{self, HeaderName};
+use lettre::message::Mailboxes;
 use lettre::transport::smtp::authentication::Credentials;
-use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use lettre::{AsyncSmtpTransport, Message, Tokio1Executor};
 
-use super::config::{SmtpConfig, SmtpEncryption};
+use super::config::{DkimDomain, SmtpConfig, SmtpEncryption};
+use super::raw::RawMessage;
 
 pub struct Mailer {
     pub transport: AsyncSmtpTransport<Tokio1Executor>,
     pub config: SmtpConfig,
+    dkim: DkimSigner,
 }
 
 impl Mailer {
@@ -27,9 +37,209 @@ impl Mailer {
         if let Some(port) = &config.port {
             sender = sender.port(*port)
         }
+        let dkim = DkimSigner {
+            keys: config
+                .dkim
+                .iter()
+                .map(|dkim_domain| {
+                    let key =
+                        fs::read_to_string(&dkim_domain.private_key_file).unwrap_or_else(|e| {
+                            panic!(
+                                "cannot read DKIM key {}: {}",
+                                dkim_domain.private_key_file.display(),
+                                e
+                            )
+                        });
+                    (
+                        dkim_domain.domain.clone(),
+                        dkim_config(dkim_domain, &key, &config.dkim_headers),
+                    )
+                })
+                .collect(),
+        };
         Mailer {
             transport: sender.build(),
             config,
+            dkim,
+        }
+    }
+
+    /// Adds a DKIM signature if a key is configured for the sender domain.
+    pub fn sign(&self, message: &mut Message) {
+        self.dkim.sign(message);
+    }
+
+    /// Like [`Mailer::sign`], for messages submitted as is.
+    pub fn sign_raw(&self, raw: &mut RawMessage) {
+        self.dkim.sign_raw(raw)
+    }
+}
+
+/// DKIM signing configs by domain.
+struct DkimSigner {
+    keys: Vec<(String, DkimConfig)>,
+}
+
+impl DkimSigner {
+    /// Signs with the key of the From address domain or one of its parent domains,
+    /// the most specific domain wins.
+    fn sign(&self, message: &mut Message) -> bool {
+        let Some(from) = message.headers().get::<header::From>() else {
+            return false;
+        };
+        let Some(mailbox) = Mailboxes::from(from).into_iter().next() else {
+            return false;
+        };
+        let from_domain = mailbox.email.domain().to_lowercase();
+        let dkim = self
+            .keys
+            .iter()
+            .filter(|(domain, _)| {
+                from_domain == *domain || from_domain.ends_with(&format!(".{}", domain))
+            })
+            .max_by_key(|(domain, _)| domain.len());
+        match dkim {
+            Some((_, dkim_config)) => {
+                dkim_sign(message, dkim_config);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // lettre only signs its own messages, so a copy is signed and the signature moved over
+    fn sign_raw(&self, raw: &mut RawMessage) {
+        let Some(mut message) = raw.to_message() else {
+            return;
+        };
+        if self.sign(&mut message) {
+            if let Some(signature) = message.headers().get_raw("DKIM-Signature") {
+                raw.prepend_header("DKIM-Signature", signature);
+            }
         }
     }
 }
+
+// PKCS#1 PEM files hold RSA keys, anything else is read as base64 encoded Ed25519 key
+fn dkim_config(dkim_domain: &DkimDomain, key: &str, headers: &[String]) -> DkimConfig {
+    let algorithm = if key.contains("-----BEGIN") {
+        DkimSigningAlgorithm::Rsa
+    } else {
+        DkimSigningAlgorithm::Ed25519
+    };
+    let key = DkimSigningKey::new(key.trim(), algorithm).unwrap_or_else(|e| {
+        panic!(
+            "invalid DKIM key {} (RSA keys must be PKCS#1 PEM): {}",
+            dkim_domain.private_key_file.display(),
+            e
+        )
+    });
+    DkimConfig::new(
+        dkim_domain.selector.clone(),
+        dkim_domain.domain.clone(),
+        key,
+        headers
+            .iter()
+            .map(|name| {
+                HeaderName::new_from_ascii(name.clone())
+                    .unwrap_or_else(|_| panic!("invalid header name \"{}\" in DKIM_HEADERS", name))
+            })
+            .collect(),
+        DkimCanonicalization {
+            header: DkimCanonicalizationType::Relaxed,
+            body: DkimCanonicalizationType::Relaxed,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_signer(domains: &[&str]) -> DkimSigner {
+        let headers = ["From".to_string(), "Subject".to_string()];
+        DkimSigner {
+            keys: domains
+                .iter()
+                .map(|domain| {
+                    let dkim_domain = DkimDomain {
+                        domain: domain.to_string(),
+                        selector: format!("sel-{}", domain),
+                        private_key_file: "test.key".into(),
+                    };
+                    // any 32 bytes form a valid Ed25519 key
+                    let key = "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=";
+                    (domain.to_string(), dkim_config(&dkim_domain, key, &headers))
+                })
+                .collect(),
+        }
+    }
+
+    fn signature(signer: &DkimSigner, from: &str) -> Option<String> {
+        let mut message = Message::builder()
+            .from(from.parse().unwrap())
+            .to("rcpt@example.net".parse().unwrap())
+            .subject("Test")
+            .body("Hi there".to_string())
+            .unwrap();
+        signer.sign(&mut message);
+        message
+            .headers()
+            .get_raw("DKIM-Signature")
+            .map(str::to_string)
+    }
+
+    #[test]
+    fn signs_with_the_most_specific_domain() {
+        let signer = test_signer(&["example.org", "news.example.org", "example.com"]);
+
+        let sig = signature(&signer, "Jane <jane@Example.org>").unwrap();
+        assert!(sig.contains("d=example.org;"));
+        assert!(sig.contains("s=sel-example.org;"));
+        assert!(sig.contains("a=ed25519-sha256;"));
+        assert!(sig.contains("h=from:subject;"));
+
+        let sig = signature(&signer, "info@mail.news.example.org").unwrap();
+        assert!(sig.contains("d=news.example.org;"));
+
+        assert!(signature(&signer, "jane@example.net").is_none());
+        assert!(signature(&signer, "jane@notexample.org").is_none());
+    }
+
+    #[test]
+    fn signs_raw_messages_like_built_ones() {
+        let signer = test_signer(&["example.org"]);
+        let tag = |sig: &str, name: &str| {
+            sig.split("; ")
+                .find_map(|tag| tag.strip_prefix(name))
+                .map(str::to_string)
+        };
+
+        let mut raw = crate::raw::parse(
+            b"DKIM-Signature: v=1; d=upstream.example\r\n\
+              From: Jane <jane@example.org>\r\n\
+              To: rcpt@example.net\r\n\
+              Subject:  Test \r\n\
+              \r\n\
+              Hi there",
+            None,
+            &[],
+        )
+        .unwrap();
+        signer.sign_raw(&mut raw);
+        let formatted = String::from_utf8(raw.formatted).unwrap();
+        let (sig, rest) = formatted
+            .strip_prefix("DKIM-Signature: ")
+            .unwrap()
+            .split_once("\r\nDKIM-Signature: v=1; d=upstream.example\r\n")
+            .unwrap();
+        let sig = sig.replace("\r\n", "");
+        assert!(rest.ends_with("Subject:  Test \r\n\r\nHi there"));
+
+        // same fields and body, so the hashes match those of lettre's own signature
+        let built = signature(&signer, "Jane <jane@example.org>").unwrap();
+        assert_eq!(tag(&sig, "d="), Some("example.org".into()));
+        assert_eq!(tag(&sig, "h="), tag(&built, "h="));
+        assert_eq!(tag(&sig, "bh="), tag(&built, "bh="));
+    }
+}
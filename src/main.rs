@@ -1,30 +1,60 @@
 #[macro_use]
 extern crate rocket;
 
+mod address;
 mod auth;
+mod calendar;
 mod config;
+mod datauri;
+mod headers;
+mod html;
+mod idempotency;
 mod mailer;
+mod openpgp;
+mod plaintext;
+mod problem;
+mod queue;
+mod raw;
+mod recipients;
+mod request;
+mod response;
+mod smime;
+mod state;
 mod swagger;
+mod templates;
+mod unsubscribe;
 
-use std::ffi::OsString;
-use std::fs;
-use std::path::Path;
+use std::collections::HashMap;
+use std::future::Future;
 
 use rocket::{
-    form::Form,
-    fs::{FileServer, TempFile},
+    data::{Data, ToByteUnit},
+    form::{Form, Lenient},
+    fs::FileServer,
     http::Status,
-    serde::{json::Json, Deserialize},
+    response::content::RawHtml,
+    serde::{
+        json::{serde_json, Json},
+        Serialize,
+    },
     Request, State,
 };
 
+use lettre::{message::MessageBuilder, Message};
 use lettre::{
-    message::{Attachment, Mailbox, MultiPart, SinglePart},
+    message::{header::MessageId, Attachment, Mailbox, MultiPart, SinglePart},
     Address,
 };
-use lettre::{AsyncTransport, Message};
 
 use auth::{ApiAuth, ApiTokenConfig};
+use idempotency::{Claim, Idempotency, IdempotencyKey};
+use problem::Problem;
+use request::{
+    parse_content_type, parse_send_at, AttachmentFile, MailParameterForm, MailParameterJson,
+    MailRequest, Protection,
+};
+use response::{send_response, SendResponse};
+use state::MailState;
 
 #[rocket::main]
 async fn main() -> Result<(), Box<rocket::Error>> {
@@ -32,7 +62,7 @@ async fn main() -> Result<(), Box<rocket::Error>> {
     let api_token = ApiTokenConfig::from_env();
     swagger::generate_api_doc(api_token.enabled()).unwrap();
     println!(
-        "Running with SMTP Config: host={}, port={}, encryption={}, user={}, api_auth={}",
+        "Running with SMTP Config: host={}, port={}, encryption={}, user={}, dkim={}, api_auth={}",
         config.host,
         match config.port {
             Some(p) => p.to_string(),
@@ -43,92 +73,335 @@ async fn main() -> Result<(), Box<rocket::Error>> {
             Some(u) => u.to_string(),
             None => "(none)".to_string(),
         },
+        if config.dkim.is_empty() {
+            "(none)".to_string()
+        } else {
+            config
+                .dkim
+                .iter()
+                .map(|dkim| format!("{}/{}", dkim.domain, dkim.selector))
+                .collect::<Vec<_>>()
+                .join(",")
+        },
         if api_token.enabled() {
             "enabled"
         } else {
             "disabled"
         }
     );
+    let queue =
+        queue::Queue::open(queue::QueueConfig::from_env()).expect("cannot open queue directory");
+    println!(
+        "Running with queue: dir={}, pending={}, max_attempts={}",
+        queue.config().dir.display(),
+        queue.pending(),
+        queue.config().max_attempts
+    );
+    let templates = templates::Templates::from_env();
+    println!(
+        "Running with templates: dir={}, loaded={}",
+        templates.dir.display(),
+        templates.len()
+    );
+    let header_policy = headers::HeaderPolicy::from_env();
+    println!(
+        "Running with header denylist: {}",
+        header_policy.denylist().join(",")
+    );
+    let idempotency = Idempotency::from_env();
+    println!(
+        "Running with idempotency window: {}s",
+        idempotency.window.as_secs()
+    );
+    let smime = smime::Smime::from_env();
+    println!(
+        "Running with S/MIME: signing={}, cert_dir={}",
+        if smime.can_sign() {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        match &smime.cert_dir {
+            Some(dir) => dir.display().to_string(),
+            None => "(none)".to_string(),
+        }
+    );
+    let openpgp = openpgp::OpenPgp::from_env();
+    println!(
+        "Running with OpenPGP: signing={}, keyring_dir={}",
+        if openpgp.can_sign() {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        match &openpgp.keyring_dir {
+            Some(dir) => dir.display().to_string(),
+            None => "(none)".to_string(),
+        }
+    );
+    let plaintext = plaintext::PlainText::from_env();
+    println!(
+        "Running with text alternative from HTML: {}, width={}",
+        if plaintext.enabled {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        plaintext.width
+    );
+    let html_processing = html::HtmlProcessing::from_env();
+    println!(
+        "Running with HTML processing: inline_css={}, sanitize={}",
+        html_processing.inline_css, html_processing.sanitize
+    );
+    let unsubscribe = unsubscribe::Unsubscribe::from_env();
+    println!(
+        "Running with List-Unsubscribe: default={}, one_click={}, opt_outs={}",
+        unsubscribe.default_target.as_deref().unwrap_or("(none)"),
+        match &unsubscribe.base_url {
+            Some(url) => url.clone(),
+            None => "disabled".to_string(),
+        },
+        unsubscribe.opt_outs()
+    );
+    let recipient_policy = recipients::RecipientPolicy::from_env();
+    println!(
+        "Running with recipient policy: allowlist={}, denylist={}",
+        match recipient_policy.allowlist.is_empty() {
+            true => "(any)".to_string(),
+            false => recipient_policy.allowlist.join(","),
+        },
+        match recipient_policy.denylist.is_empty() {
+            true => "(none)".to_string(),
+            false => recipient_policy.denylist.join(","),
+        }
+    );
+    let mailer = mailer::Mailer::new(config);
+    rocket::tokio::spawn(queue.clone().run(mailer.transport.clone()));
     let _rocket = rocket::build()
-        .manage(mailer::Mailer::new(config))
-        .mount("/", routes![sendmail_form, sendmail_json])
-        .mount("/", FileServer::from("www"))
-        .register(
+        .manage(api_token)
+        .manage(mailer)
+        .manage(queue)
+        .manage(templates)
+        .manage(header_policy)
+        .manage(idempotency)
+        .manage(smime)
+        .manage(openpgp)
+        .manage(plaintext)
+        .manage(html_processing)
+        .manage(unsubscribe)
+        .manage(recipient_policy)
+        .mount(
             "/",
-            catchers![
-                not_found,
-                unauthorized,
-                payload_too_large,
-                unprocessable_entity,
-                server_error
+            routes![
+                sendmail_form,
+                sendmail_json,
+                sendmail_batch,
+                sendmail_raw,
+                message_status,
+                cancel_message,
+                get_template,
+                put_template,
+                delete_template,
+                list_template_revisions,
+                get_template_revision,
+                restore_template_revision,
+                unsubscribe_page,
+                unsubscribe_one_click
             ],
         )
+        .mount("/", FileServer::from("www"))
+        .register("/", catchers![problem_catcher])
         .launch()
         .await?;
 
     Ok(())
 }
 
-#[catch(404)]
-fn not_found(_req: &Request) -> &'static str {
-    "404 not found"
+// every error Rocket answers itself, e.g. unknown routes and failed authentication
+#[catch(default)]
+fn problem_catcher(status: Status, _req: &Request) -> Problem {
+    Problem::status(status)
 }
 
-struct Unauthorized;
+// link scanners follow links, so the link itself only shows a confirmation form
+#[get("/unsubscribe/<token>")]
+fn unsubscribe_page(
+    token: &str,
+    unsubscribe: &State<unsubscribe::Unsubscribe>,
+) -> Result<RawHtml<&'static str>, Problem> {
+    match unsubscribe.verify(token) {
+        Some(_) => Ok(RawHtml(
+            "<!DOCTYPE html><html><head><title>Unsubscribe</title></head><body>\
+             <form method=\"post\"><p>Do you want to stop receiving these mails?</p>\
+             <button type=\"submit\" name=\"List-Unsubscribe\" value=\"One-Click\">Unsubscribe</button>\
+             </form></body></html>",
+        )),
+        None => Err(invalid_unsubscribe_token()),
+    }
+}
 
-impl<'r> rocket::response::Responder<'r, 'static> for Unauthorized {
-    fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
-        Ok(rocket::Response::build()
-            .status(rocket::http::Status::Unauthorized)
-            .header(rocket::http::Header::new(
-                "WWW-Authenticate",
-                r#"Bearer realm="api""#,
-            ))
-            .sized_body(
-                "401 unauthorized".len(),
-                std::io::Cursor::new("401 unauthorized"),
-            )
-            .finalize())
+// body of a one-click POST, other fields are ignored
+#[derive(FromForm)]
+struct OneClick<'r> {
+    #[field(name = "List-Unsubscribe")]
+    list_unsubscribe: &'r str,
+}
+
+// RFC 8058 one-click, mail providers POST "List-Unsubscribe=One-Click" to the link
+#[post("/unsubscribe/<token>", data = "<body>")]
+fn unsubscribe_one_click(
+    token: &str,
+    body: Result<Form<Lenient<OneClick<'_>>>, rocket::form::Errors<'_>>,
+    unsubscribe: &State<unsubscribe::Unsubscribe>,
+) -> Result<&'static str, Problem> {
+    let address = unsubscribe
+        .verify(token)
+        .ok_or_else(invalid_unsubscribe_token)?;
+    if !matches!(&body, Ok(body) if body.list_unsubscribe == "One-Click") {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "invalid-one-click-request",
+            "the body must be List-Unsubscribe=One-Click",
+        ));
+    }
+    match unsubscribe.record(&address) {
+        Ok(()) => Ok("unsubscribed"),
+        Err(e) => Err((Status::InternalServerError, e.to_string()).into()),
     }
 }
 
-#[catch(401)]
-fn unauthorized(_: &rocket::Request<'_>) -> Unauthorized {
-    Unauthorized
+// tampered, made up or signed with another secret, or one-click is not configured
+fn invalid_unsubscribe_token() -> Problem {
+    Problem::new(
+        Status::NotFound,
+        "invalid-unsubscribe-token",
+        "unknown unsubscribe link",
+    )
 }
 
-#[catch(413)]
-fn payload_too_large(_req: &Request) -> &'static str {
-    "413 payload too large"
+#[get("/messages/<id>")]
+fn message_status(
+    _auth: ApiAuth,
+    id: &str,
+    queue: &State<queue::Queue>,
+) -> Result<Json<queue::MessageRecord>, Problem> {
+    let unknown = || Problem::new(Status::NotFound, "unknown-message", "unknown message");
+    let id = uuid::Uuid::parse_str(id).map_err(|_| unknown())?;
+    queue.status(&id).map(Json).ok_or_else(unknown)
 }
 
-#[catch(422)]
-fn unprocessable_entity(_req: &Request) -> &'static str {
-    "422 unprocessable entity"
+#[delete("/messages/<id>")]
+async fn cancel_message(
+    _auth: ApiAuth,
+    id: &str,
+    queue: &State<queue::Queue>,
+) -> Result<Json<queue::MessageRecord>, Problem> {
+    let unknown = || Problem::new(Status::NotFound, "unknown-message", "unknown message");
+    let id = uuid::Uuid::parse_str(id).map_err(|_| unknown())?;
+    match queue.cancel(&id).await {
+        Ok(record) => Ok(Json(record)),
+        Err(queue::CancelError::UnknownMessage) => Err(unknown()),
+        Err(queue::CancelError::NotPending(status)) => Err(Problem::new(
+            Status::Conflict,
+            "message-not-pending",
+            format!(
+                "message is no longer pending (status: {})",
+                serde_json::to_value(status).unwrap_or_default()
+            ),
+        )),
+    }
 }
 
-#[catch(500)]
-fn server_error() -> &'static str {
-    "500 server error"
+fn template_error(e: templates::TemplateError) -> Problem {
+    let (status, code) = match e {
+        templates::TemplateError::UnknownTemplate(_) => (Status::NotFound, "unknown-template"),
+        templates::TemplateError::UnknownRevision(_) => (Status::NotFound, "unknown-revision"),
+        templates::TemplateError::Storage(_) => (Status::InternalServerError, "server-error"),
+        _ => (Status::UnprocessableEntity, "invalid-template"),
+    };
+    Problem::new(status, code, e.to_string())
 }
 
-// the form data might contain addresses in the form "mail1@example.org,mail2@example.org" instead of ["mail1@example.org","mail2@example.org"]
-fn extract_addrs(addrs: &[String]) -> Vec<String> {
-    if addrs.len() == 1 && addrs[0].contains(",") {
-        addrs[0]
-            .split(",")
-            .map(|addr| addr.trim().to_string())
-            .filter(|addr| !addr.is_empty())
-            .collect()
-    } else {
-        addrs.to_vec()
+#[get("/templates/<name>")]
+fn get_template(
+    _auth: ApiAuth,
+    name: &str,
+    templates: &State<templates::Templates>,
+) -> Result<Json<templates::TemplateSource>, Problem> {
+    templates.get(name).map(Json).map_err(template_error)
+}
+
+#[put("/templates/<name>", format = "json", data = "<source>")]
+fn put_template(
+    _auth: ApiAuth,
+    name: &str,
+    source: Result<Json<serde_json::Value>, rocket::serde::json::Error<'_>>,
+    templates: &State<templates::Templates>,
+) -> Result<(Status, String), Problem> {
+    let source = source.map_err(|e| Problem::from_json(&e))?.into_inner();
+    let source: templates::TemplateSource = Problem::parse_json(source)?;
+    match templates.save(name, source) {
+        Ok(true) => Ok((Status::Created, "template created".into())),
+        Ok(false) => Ok((Status::Ok, "template updated".into())),
+        Err(e) => Err(template_error(e)),
+    }
+}
+
+#[delete("/templates/<name>")]
+fn delete_template(
+    _auth: ApiAuth,
+    name: &str,
+    templates: &State<templates::Templates>,
+) -> Result<(Status, String), Problem> {
+    match templates.delete(name) {
+        Ok(()) => Ok((Status::Ok, "template deleted".into())),
+        Err(e) => Err(template_error(e)),
+    }
+}
+
+#[get("/templates/<name>/revisions")]
+fn list_template_revisions(
+    _auth: ApiAuth,
+    name: &str,
+    templates: &State<templates::Templates>,
+) -> Result<Json<Vec<templates::RevisionInfo>>, Problem> {
+    templates.revisions(name).map(Json).map_err(template_error)
+}
+
+#[get("/templates/<name>/revisions/<revision>")]
+fn get_template_revision(
+    _auth: ApiAuth,
+    name: &str,
+    revision: u32,
+    templates: &State<templates::Templates>,
+) -> Result<Json<templates::TemplateSource>, Problem> {
+    templates
+        .revision(name, revision)
+        .map(Json)
+        .map_err(template_error)
+}
+
+#[post("/templates/<name>/revisions/<revision>/restore")]
+fn restore_template_revision(
+    _auth: ApiAuth,
+    name: &str,
+    revision: u32,
+    templates: &State<templates::Templates>,
+) -> Result<(Status, String), Problem> {
+    match templates.restore(name, revision) {
+        Ok(()) => Ok((
+            Status::Ok,
+            format!("template restored from revision {}", revision),
+        )),
+        Err(e) => Err(template_error(e)),
     }
 }
 
 fn find_from_addr(
     request_value: &Option<String>,
     mailer: &mailer::Mailer,
-) -> Result<Address, (Status, String)> {
+) -> Result<Address, Problem> {
     let from_addr = match &request_value {
         Some(fa) => Some(fa),
         None => mailer.config.username.as_ref(),
@@ -138,258 +411,791 @@ fn find_from_addr(
         if let Ok(from_addr) = from_addr.parse::<Address>() {
             Ok(from_addr)
         } else {
-            Err((Status::UnprocessableEntity, "from_address invalid".into()))
+            Err(Problem::invalid("from_address", "invalid address"))
         }
     } else {
-        Err((
-            Status::UnprocessableEntity,
-            "from_address missing and no default configured".into(),
+        Err(Problem::invalid(
+            "from_address",
+            "missing and no default configured",
         ))
     }
 }
 
-#[derive(FromForm)]
-struct MailParameterForm<'r> {
-    #[field(validate = len(1..))]
-    subject: String,
-    #[field(name = "attachment")]
-    attachments: Vec<TempFile<'r>>,
-    from_address: Option<String>,
-    from_name: Option<String>,
-    #[field(validate = len(1..), name = "to_address")]
-    to_addresses: Vec<String>,
-    #[field(name = "cc_address")]
-    cc_addresses: Vec<String>,
-    #[field(name = "bcc_address")]
-    bcc_addresses: Vec<String>,
-    content_html: Option<String>,
-    content_text: Option<String>,
+// subject and content either come from the request or from a rendered template
+#[allow(clippy::too_many_arguments)]
+fn find_content(
+    subject: &Option<String>,
+    content_text: &Option<String>,
+    content_html: &Option<String>,
+    content_markdown: &Option<String>,
+    template: &Option<String>,
+    variables: &serde_json::Value,
+    templates: &templates::Templates,
+    html_processing: &html::HtmlProcessing,
+) -> Result<templates::Rendered, Problem> {
+    let content = match (template, content_markdown) {
+        (Some(name), _) => {
+            if content_text.is_some() || content_html.is_some() || content_markdown.is_some() {
+                return Err(Problem::invalid(
+                    "template",
+                    "cannot be combined with content_html, content_text or content_markdown",
+                ));
+            }
+            let mut rendered = templates
+                .render(name, variables)
+                .map_err(|e| Problem::invalid("template", e.to_string()))?;
+            if let Some(subject) = subject {
+                rendered.subject = subject.clone();
+            }
+            rendered
+        }
+        // the Markdown source doubles as the plain text part
+        (None, Some(markdown)) => {
+            if content_text.is_some() || content_html.is_some() {
+                return Err(Problem::invalid(
+                    "content_markdown",
+                    "cannot be combined with content_html or content_text",
+                ));
+            }
+            templates::Rendered {
+                subject: subject.clone().unwrap_or_default(),
+                html: Some(html_processing.markdown(markdown)),
+                text: Some(markdown.clone()),
+            }
+        }
+        (None, None) => templates::Rendered {
+            subject: subject.clone().unwrap_or_default(),
+            html: content_html.clone(),
+            text: content_text.clone(),
+        },
+    };
+
+    // manual data validation required, https://github.com/SergioBenitez/Rocket/issues/1915
+    if content.subject.chars().count() < 1 {
+        return Err(Problem::invalid("subject", "missing or empty"));
+    }
+    Ok(content)
 }
 
-#[post("/send", format = "multipart/form-data", data = "<request_params>")]
-async fn sendmail_form(
-    _auth: ApiAuth,
-    request_params: Result<Form<MailParameterForm<'_>>, rocket::form::Errors<'_>>,
-    mailer: &State<mailer::Mailer>,
-) -> (Status, String) {
-    match request_params {
-        Ok(params) => {
-            let from_addr = match find_from_addr(&params.from_address, mailer) {
-                Ok(addr) => addr,
-                Err((status, msg)) => return (status, msg),
-            };
-
-            let from_mailbox = Mailbox::new(params.from_name.clone(), from_addr);
-
-            let mut m = Message::builder()
-                .from(from_mailbox)
-                .subject(&params.subject);
-            for to_address in extract_addrs(&params.to_addresses) {
-                if let Ok(addr) = to_address.parse() {
-                    m = m.to(addr);
-                } else {
-                    return (Status::UnprocessableEntity, "invalid to_address".into());
-                }
+// Reply-To and the caller supplied headers, checked against the header policy
+fn add_headers(
+    mut m: MessageBuilder,
+    reply_to: &Option<String>,
+    headers: &HashMap<String, String>,
+    header_policy: &headers::HeaderPolicy,
+) -> Result<MessageBuilder, Problem> {
+    if let Some(reply_to) = reply_to {
+        match reply_to.parse::<Mailbox>() {
+            Ok(mailbox) => m = m.reply_to(mailbox),
+            Err(_) => return Err(Problem::invalid("reply_to", "invalid address")),
+        }
+    }
+    for header in header_policy
+        .check(headers)
+        .map_err(|e| Problem::invalid("headers", e))?
+    {
+        m = m.raw_header(header);
+    }
+    Ok(m)
+}
+
+// recipients that are not allowed by the policy or that unsubscribed are refused on every
+// send path, whether the mail carries List-Unsubscribe or not
+fn check_recipients(
+    recipients: &[Address],
+    recipient_policy: &recipients::RecipientPolicy,
+    unsubscribe: &unsubscribe::Unsubscribe,
+) -> Result<(), Problem> {
+    let rejected = recipient_policy.rejected(recipients);
+    if !rejected.is_empty() {
+        return Err(Problem::forbidden_recipients(
+            "recipient-not-allowed",
+            "recipients not allowed",
+            rejected,
+        ));
+    }
+    let unsubscribed = unsubscribe.unsubscribed(recipients);
+    if !unsubscribed.is_empty() {
+        return Err(Problem::forbidden_recipients(
+            "recipient-unsubscribed",
+            "unsubscribed recipients",
+            unsubscribed,
+        ));
+    }
+    Ok(())
+}
+
+// List-Unsubscribe from the request or the global LIST_UNSUBSCRIBE default
+fn add_list_unsubscribe(
+    m: MessageBuilder,
+    target: &Option<String>,
+    one_click: bool,
+    unsubscribe: &unsubscribe::Unsubscribe,
+) -> Result<MessageBuilder, Problem> {
+    if target.is_none() && !one_click && unsubscribe.default_target.is_none() {
+        return Ok(m);
+    }
+    // Bcc recipients are only known from the envelope of the built message
+    let recipients = m
+        .clone()
+        .body(String::new())
+        .map_err(|e| Problem::from((Status::UnprocessableEntity, e.to_string())))?
+        .envelope()
+        .to()
+        .to_vec();
+    let headers = unsubscribe
+        .headers(target.as_deref(), one_click, &recipients)
+        .map_err(|(field, e)| Problem::invalid(field, e))?;
+    Ok(headers
+        .into_iter()
+        .fold(m, |m, header| m.raw_header(header)))
+}
+
+// a repeated Idempotency-Key is answered with the stored response instead of sending again
+async fn idempotent(
+    idempotency: &Idempotency,
+    key: &IdempotencyKey,
+    fingerprint: u64,
+    send: impl Future<Output = idempotency::Response>,
+) -> idempotency::Response {
+    let Some(key) = &key.0 else {
+        return send.await;
+    };
+    if key.is_empty() || key.len() > idempotency::MAX_KEY_LENGTH {
+        return Err(Problem::new(
+            Status::UnprocessableEntity,
+            "invalid-idempotency-key",
+            format!(
+                "Idempotency-Key must have 1 to {} characters",
+                idempotency::MAX_KEY_LENGTH
+            ),
+        ));
+    }
+    match idempotency.claim(key, fingerprint) {
+        Claim::New => {
+            let response = send.await;
+            idempotency.complete(key, &response);
+            response
+        }
+        Claim::Replay(response) => response,
+        Claim::Mismatch => Err(Problem::new(
+            Status::Conflict,
+            "idempotency-key-reused",
+            "Idempotency-Key was already used with a different payload",
+        )),
+        Claim::InProgress => Err(Problem::new(
+            Status::Conflict,
+            "idempotency-key-in-progress",
+            "a request with this Idempotency-Key is still in progress",
+        )),
+    }
+}
+
+// S/MIME and OpenPGP protection has to happen before DKIM signing, which covers the final body
+fn finish_mail(
+    m: MessageBuilder,
+    body: MultiPart,
+    protection: Protection,
+    smime: &smime::Smime,
+    openpgp: &openpgp::OpenPgp,
+    mailer: &mailer::Mailer,
+) -> Result<Message, Problem> {
+    let use_smime = protection.smime_sign || protection.smime_encrypt;
+    let use_pgp = protection.pgp_sign || protection.pgp_encrypt;
+    if use_smime && use_pgp {
+        return Err(Problem::new(
+            Status::UnprocessableEntity,
+            "conflicting-protection",
+            "S/MIME and OpenPGP cannot be combined",
+        ));
+    }
+    // Bcc recipients are only known from the envelope of the built message
+    let recipients = match protection.smime_encrypt || protection.pgp_encrypt {
+        true => Some(
+            m.clone()
+                .multipart(body.clone())
+                .map_err(|e| Problem::from((Status::InternalServerError, e.to_string())))?
+                .envelope()
+                .to()
+                .to_vec(),
+        ),
+        false => None,
+    };
+    let mail = if use_smime {
+        match smime.protect(body, protection.smime_sign, recipients.as_deref()) {
+            Ok(smime::Protected::Multipart(body)) => m.multipart(body),
+            Ok(smime::Protected::Singlepart(part)) => m.singlepart(part),
+            Err(e @ smime::SmimeError::Crypto(_)) => {
+                return Err((Status::InternalServerError, e.to_string()).into())
+            }
+            Err(e @ smime::SmimeError::SigningNotConfigured) => {
+                return Err(Problem::invalid("smime_sign", e.to_string()))
+            }
+            Err(e @ smime::SmimeError::EncryptionNotConfigured) => {
+                return Err(Problem::invalid("smime_encrypt", e.to_string()))
+            }
+            Err(e @ smime::SmimeError::MissingCertificate(_)) => {
+                return Err(Problem::new(
+                    Status::UnprocessableEntity,
+                    "missing-recipient-key",
+                    e.to_string(),
+                ))
+            }
+        }
+    } else if use_pgp {
+        match openpgp.protect(body, protection.pgp_sign, recipients.as_deref()) {
+            Ok(body) => m.multipart(body),
+            Err(e @ openpgp::PgpError::Crypto(_)) => {
+                return Err((Status::InternalServerError, e.to_string()).into())
             }
-            for cc_address in extract_addrs(&params.cc_addresses) {
-                if let Ok(addr) = cc_address.parse() {
-                    m = m.cc(addr);
-                } else {
-                    return (Status::UnprocessableEntity, "invalid cc_address".into());
-                }
+            Err(e @ openpgp::PgpError::SigningNotConfigured) => {
+                return Err(Problem::invalid("pgp_sign", e.to_string()))
             }
-            for bcc_address in extract_addrs(&params.bcc_addresses) {
-                if let Ok(addr) = bcc_address.parse() {
-                    m = m.bcc(addr);
-                } else {
-                    return (Status::UnprocessableEntity, "invalid bcc_address".into());
-                }
+            Err(e @ openpgp::PgpError::EncryptionNotConfigured) => {
+                return Err(Problem::invalid("pgp_encrypt", e.to_string()))
             }
+            Err(e @ openpgp::PgpError::MissingKey(_)) => {
+                return Err(Problem::new(
+                    Status::UnprocessableEntity,
+                    "missing-recipient-key",
+                    e.to_string(),
+                ))
+            }
+        }
+    } else {
+        m.multipart(body)
+    };
+    let mut mail = mail.map_err(|e| Problem::from((Status::InternalServerError, e.to_string())))?;
+    // unless given via headers, before DKIM signing since the signature covers it
+    if mail.headers().get::<MessageId>().is_none() {
+        let domain = mail
+            .envelope()
+            .from()
+            .map_or("rest2smtp", |from| from.domain())
+            .to_string();
+        mail.headers_mut().set(MessageId::from(format!(
+            "<{}@{}>",
+            uuid::Uuid::new_v4().simple(),
+            domain
+        )));
+    }
+    mailer.sign(&mut mail);
+    Ok(mail)
+}
 
-            let multipart = match (&params.content_text, &params.content_html) {
-                (Some(txt), Some(html)) => MultiPart::alternative()
-                    .singlepart(SinglePart::plain(txt.clone()))
-                    .singlepart(SinglePart::html(html.clone())),
-
-                (Some(txt), None) => {
-                    MultiPart::alternative().singlepart(SinglePart::plain(txt.clone()))
-                }
-
-                (None, Some(html)) => {
-                    MultiPart::alternative().singlepart(SinglePart::html(html.clone()))
-                }
-
-                (None, None) => MultiPart::alternative().build(),
-            };
-            let mail_body = if !params.attachments.is_empty() {
-                let mut attachments = MultiPart::mixed().multipart(multipart);
-                for attachment in &params.attachments {
-                    attachments = attachments.singlepart(
-                        Attachment::new(match attachment.name() {
-                            Some(safe_name) => {
-                                let name_no_ext = Path::new(safe_name);
-                                let unsafe_name = attachment
-                                    .raw_name()
-                                    .unwrap_or("".into())
-                                    .dangerous_unsafe_unsanitized_raw()
-                                    .as_str();
-                                let ext_part = Path::new(unsafe_name).extension();
-                                let extension = match ext_part {
-                                    Some(ext) => ext.to_os_string(),
-                                    None => OsString::from(""),
-                                };
-                                name_no_ext
-                                    .with_extension(extension)
-                                    .into_os_string()
-                                    .into_string()
-                                    .unwrap_or(safe_name.into())
-                            }
-                            None => "attachment".to_string(),
-                        })
-                        .body(
-                            match attachment {
-                                TempFile::File { path, .. } => fs::read(path).unwrap(),
-                                TempFile::Buffered { content } => content.to_vec(),
-                            },
-                            match &attachment.content_type() {
-                                Some(content_type) => {
-                                    content_type.to_string().parse().unwrap_or_else(|_| {
-                                        "application/octet-stream".parse().unwrap()
-                                    })
-                                }
-                                None => "application/octet-stream".parse().unwrap(),
-                            },
-                        ),
-                    );
-                }
-                attachments
-            } else {
-                multipart
-            };
-
-            match m.multipart(mail_body) {
-                Ok(mail) => match mailer.transport.send(mail).await {
-                    Ok(x) => (Status::Ok, x.first_line().unwrap_or("").to_string()),
-                    Err(e) => (Status::InternalServerError, e.to_string()),
-                },
-                Err(e) => (Status::InternalServerError, e.to_string()),
+// builds mixed(alternative(text, related(html, inline...)), attachments...), leaving out
+// the wrappers that are not needed
+fn build_body(
+    text: Option<String>,
+    mut html: Option<String>,
+    calendar: Option<SinglePart>,
+    mut attachments: Vec<AttachmentFile>,
+    inline_data_uris: bool,
+) -> Result<MultiPart, Problem> {
+    if let (true, Some(content)) = (inline_data_uris, &html) {
+        let (rewritten, images) = datauri::extract_images(content);
+        attachments.extend(images.into_iter().map(|image| AttachmentFile {
+            filename: image.filename,
+            content_id: Some(image.content_id),
+            content_type: parse_content_type(Some(image.content_type)),
+            body: image.body,
+        }));
+        html = Some(rewritten);
+    }
+
+    let (inline, attachments): (Vec<_>, Vec<_>) = attachments
+        .into_iter()
+        .partition(|attachment| attachment.content_id.is_some());
+
+    let mut alternative = MultiPart::alternative().build();
+    if let Some(txt) = text {
+        alternative = alternative.singlepart(SinglePart::plain(txt));
+    }
+    match html {
+        Some(html) if inline.is_empty() => {
+            alternative = alternative.singlepart(SinglePart::html(html));
+        }
+        Some(html) => {
+            let mut related = MultiPart::related().singlepart(SinglePart::html(html));
+            for attachment in inline {
+                related = related.singlepart(
+                    Attachment::new_inline_with_name(
+                        attachment.content_id.unwrap_or_default(),
+                        attachment.filename,
+                    )
+                    .body(attachment.body, attachment.content_type),
+                );
             }
+            alternative = alternative.multipart(related);
         }
-        Err(errors) => {
-            let err_text = errors
-                .iter()
-                .map(|err| format!("{:?}", err))
-                .collect::<Vec<_>>()
-                .join("\n");
-            (Status::UnprocessableEntity, err_text)
+        None if !inline.is_empty() => {
+            return Err(Problem::invalid(
+                "content_html",
+                "required for inline attachments",
+            ));
         }
+        None => {}
+    }
+    // invitations are another alternative, calendar clients show them as event
+    if let Some(calendar) = calendar {
+        alternative = alternative.singlepart(calendar);
+    }
+
+    if attachments.is_empty() {
+        return Ok(alternative);
+    }
+    let mut mixed = MultiPart::mixed().multipart(alternative);
+    for attachment in attachments {
+        mixed = mixed.singlepart(
+            Attachment::new(attachment.filename).body(attachment.body, attachment.content_type),
+        );
     }
+    Ok(mixed)
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(crate = "rocket::serde")]
-struct MailParameterJson {
-    subject: String,
-    from_address: Option<String>,
-    from_name: Option<String>,
-    to_addresses: Vec<String>,
-    cc_addresses: Option<Vec<String>>,
-    bcc_addresses: Option<Vec<String>>,
-    content_html: Option<String>,
-    content_text: Option<String>,
+#[post("/send", format = "multipart/form-data", data = "<request_params>")]
+async fn sendmail_form(
+    _auth: ApiAuth,
+    idempotency_key: IdempotencyKey,
+    format: response::Format,
+    request_params: Result<Form<MailParameterForm<'_>>, rocket::form::Errors<'_>>,
+    state: MailState<'_>,
+) -> SendResponse {
+    let request = match request_params {
+        Ok(params) => MailRequest::from_form(&params),
+        Err(errors) => Err(Problem::from_form(&errors)),
+    };
+    send_mail(request, &idempotency_key, format, &state).await
 }
 
 #[post("/send", format = "json", data = "<request_params>")]
 async fn sendmail_json(
     _auth: ApiAuth,
-    request_params: Result<Json<MailParameterJson>, rocket::serde::json::Error<'_>>,
-    mailer: &State<mailer::Mailer>,
-) -> (Status, String) {
-    match request_params {
-        Ok(params) => {
-            // manual data validation required, https://github.com/SergioBenitez/Rocket/issues/1915
-            if params.subject.chars().count() < 1 {
-                return (
-                    Status::UnprocessableEntity,
-                    "subject missing or empty".into(),
-                );
-            }
+    idempotency_key: IdempotencyKey,
+    format: response::Format,
+    request_params: Result<Json<serde_json::Value>, rocket::serde::json::Error<'_>>,
+    state: MailState<'_>,
+) -> SendResponse {
+    let request = match request_params {
+        Ok(params) => Problem::parse_json(params.into_inner()).and_then(MailRequest::from_json),
+        Err(e) => Err(Problem::from_json(&e)),
+    };
+    send_mail(request, &idempotency_key, format, &state).await
+}
 
-            let from_addr = match find_from_addr(&params.from_address, mailer) {
-                Ok(addr) => addr,
-                Err((status, msg)) => return (status, msg),
-            };
-            if params.to_addresses.is_empty() {
-                return (
-                    Status::UnprocessableEntity,
-                    "to_addresses missing or empty".into(),
-                );
-            } else {
-                for to_addr in &params.to_addresses {
-                    if to_addr.chars().count() < 3 {
-                        return (
-                            Status::UnprocessableEntity,
-                            "to_addresses contains invalid address".into(),
-                        );
+// both content types of POST /send differ only in how the request is decoded
+async fn send_mail(
+    request: Result<MailRequest, Problem>,
+    idempotency_key: &IdempotencyKey,
+    format: response::Format,
+    state: &MailState<'_>,
+) -> SendResponse {
+    let response = match request {
+        Ok(request) => {
+            idempotent(
+                state.idempotency,
+                idempotency_key,
+                request.fingerprint(),
+                async {
+                    let send_at = request.send_at;
+                    let mail = build_mail(request, state)?;
+                    match state.queue.enqueue(mail, send_at).await {
+                        Ok(id) => Ok((Status::Accepted, id.to_string())),
+                        Err(e) => Err((Status::InternalServerError, e.to_string()).into()),
                     }
-                }
-            }
+                },
+            )
+            .await
+        }
+        Err(problem) => Err(problem),
+    };
+    send_response(format, state.queue, response)
+}
 
-            let from_mailbox = Mailbox::new(params.from_name.clone(), from_addr);
-
-            let mut m = Message::builder()
-                .from(from_mailbox)
-                .subject(&params.subject);
-            for to_address in &params.to_addresses {
-                if let Ok(addr) = to_address.parse() {
-                    m = m.to(addr);
-                } else {
-                    return (Status::UnprocessableEntity, "invalid to_address".into());
-                }
-            }
-            if let Some(cc_addrs) = &params.cc_addresses {
-                for cc_address in cc_addrs {
-                    if let Ok(addr) = cc_address.parse() {
-                        m = m.cc(addr);
-                    } else {
-                        return (Status::UnprocessableEntity, "invalid cc_address".into());
-                    }
-                }
-            }
-            if let Some(bcc_addrs) = &params.bcc_addresses {
-                for bcc_address in bcc_addrs {
-                    if let Ok(addr) = bcc_address.parse() {
-                        m = m.bcc(addr);
-                    } else {
-                        return (Status::UnprocessableEntity, "invalid bcc_address".into());
-                    }
-                }
-            }
+// the message for a validated request of either content type
+fn build_mail(request: MailRequest, state: &MailState<'_>) -> Result<Message, Problem> {
+    let recipients: Vec<Address> = [&request.to, &request.cc, &request.bcc]
+        .into_iter()
+        .flatten()
+        .map(|mailbox| mailbox.email.clone())
+        .collect();
+    check_recipients(&recipients, state.recipient_policy, state.unsubscribe)?;
+    let content = find_content(
+        &request.subject,
+        &request.content_text,
+        &request.content_html,
+        &request.content_markdown,
+        &request.template,
+        &request.variables,
+        state.templates,
+        state.html_processing,
+    )?;
 
-            let multipart = match (&params.content_text, &params.content_html) {
-                (Some(txt), Some(html)) => MultiPart::alternative()
-                    .singlepart(SinglePart::plain(txt.clone()))
-                    .singlepart(SinglePart::html(html.clone())),
+    let from_addr = find_from_addr(&request.from_address, state.mailer)?;
+    let from_mailbox = Mailbox::new(request.from_name, from_addr);
+    let calendar = request
+        .event
+        .as_ref()
+        .map(|event| event.part(&from_mailbox))
+        .transpose()
+        .map_err(|msg| Problem::invalid("event", msg))?;
 
-                (Some(txt), None) => {
-                    MultiPart::alternative().singlepart(SinglePart::plain(txt.clone()))
-                }
+    let mut m = Message::builder()
+        .from(from_mailbox)
+        .subject(content.subject);
+    for to in request.to {
+        m = m.to(to);
+    }
+    for cc in request.cc {
+        m = m.cc(cc);
+    }
+    for bcc in request.bcc {
+        m = m.bcc(bcc);
+    }
+    let m = add_headers(m, &request.reply_to, &request.headers, state.header_policy)?;
+    let m = add_list_unsubscribe(
+        m,
+        &request.list_unsubscribe,
+        request.one_click_unsubscribe,
+        state.unsubscribe,
+    )?;
 
-                (None, Some(html)) => {
-                    MultiPart::alternative().singlepart(SinglePart::html(html.clone()))
-                }
+    let html = content
+        .html
+        .map(|html| {
+            state
+                .html_processing
+                .process(html, request.inline_css, request.sanitize_html)
+        })
+        .transpose()
+        .map_err(|msg| Problem::invalid("content_html", msg))?;
+    let text = state
+        .plaintext
+        .alternative(content.text, html.as_deref(), request.text_from_html);
+    let mail_body = build_body(
+        text,
+        html,
+        calendar,
+        request.attachments,
+        request.inline_data_uris,
+    )?;
+    finish_mail(
+        m,
+        mail_body,
+        request.protection,
+        state.smime,
+        state.openpgp,
+        state.mailer,
+    )
+}
 
-                (None, None) => MultiPart::alternative().build(),
-            };
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct BatchItemResult {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<uuid::Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Problem>,
+}
 
-            match m.multipart(multipart) {
-                Ok(mail) => match mailer.transport.send(mail).await {
-                    Ok(x) => (Status::Ok, x.first_line().unwrap_or("").to_string()),
-                    Err(e) => (Status::InternalServerError, e.to_string()),
+impl From<Problem> for BatchItemResult {
+    fn from(problem: Problem) -> Self {
+        BatchItemResult {
+            status: problem.status,
+            id: None,
+            message_id: None,
+            error: Some(problem),
+        }
+    }
+}
+
+// items are deserialized one by one, so a malformed item does not reject the whole batch
+#[post("/send/batch", format = "json", data = "<request_params>")]
+async fn sendmail_batch(
+    _auth: ApiAuth,
+    request_params: Result<Json<Vec<serde_json::Value>>, rocket::serde::json::Error<'_>>,
+    state: MailState<'_>,
+) -> Result<Json<Vec<BatchItemResult>>, Problem> {
+    let items = request_params
+        .map_err(|e| Problem::from_json(&e))?
+        .into_inner();
+
+    let mut results = Vec::with_capacity(items.len());
+    for item in items {
+        let mail = Problem::parse_json::<MailParameterJson>(item)
+            .and_then(MailRequest::from_json)
+            .and_then(|request| {
+                let send_at = request.send_at;
+                Ok((build_mail(request, &state)?, send_at))
+            });
+        results.push(match mail {
+            Ok((mail, send_at)) => match state.queue.enqueue(mail, send_at).await {
+                Ok(id) => BatchItemResult {
+                    status: Status::Accepted.code,
+                    id: Some(id),
+                    message_id: state.queue.status(&id).and_then(|record| record.message_id),
+                    error: None,
                 },
-                Err(e) => (Status::InternalServerError, e.to_string()),
-            }
+                Err(e) => Problem::from((Status::InternalServerError, e.to_string())).into(),
+            },
+            Err(problem) => problem.into(),
+        });
+    }
+    Ok(Json(results))
+}
+
+// complete messages are queued as submitted, only the envelope is derived from them
+#[allow(clippy::too_many_arguments)]
+#[post(
+    "/send/raw?<envelope_from>&<envelope_to>&<send_at>",
+    format = "message/rfc822",
+    data = "<message>"
+)]
+async fn sendmail_raw(
+    _auth: ApiAuth,
+    format: response::Format,
+    envelope_from: Option<String>,
+    envelope_to: Vec<String>,
+    send_at: Option<String>,
+    message: Data<'_>,
+    limits: &rocket::data::Limits,
+    queue: &State<queue::Queue>,
+    mailer: &State<mailer::Mailer>,
+    recipient_policy: &State<recipients::RecipientPolicy>,
+    unsubscribe: &State<unsubscribe::Unsubscribe>,
+) -> SendResponse {
+    send_response(
+        format,
+        queue,
+        queue_raw(
+            envelope_from,
+            envelope_to,
+            send_at,
+            message,
+            limits,
+            queue,
+            mailer,
+            recipient_policy,
+            unsubscribe,
+        )
+        .await,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn queue_raw(
+    envelope_from: Option<String>,
+    envelope_to: Vec<String>,
+    send_at: Option<String>,
+    message: Data<'_>,
+    limits: &rocket::data::Limits,
+    queue: &queue::Queue,
+    mailer: &mailer::Mailer,
+    recipient_policy: &recipients::RecipientPolicy,
+    unsubscribe: &unsubscribe::Unsubscribe,
+) -> idempotency::Response {
+    let send_at = parse_send_at(&send_at)?;
+    let limit = limits.get("message").unwrap_or(50.mebibytes());
+    let message = match message.open(limit).into_bytes().await {
+        Ok(message) if message.is_complete() => message.into_inner(),
+        Ok(_) => return Err((Status::PayloadTooLarge, "message too large".to_string()).into()),
+        Err(e) => return Err((Status::BadRequest, e.to_string()).into()),
+    };
+
+    let mut raw = raw::parse(
+        &message,
+        envelope_from.as_deref(),
+        &address::split(&envelope_to),
+    )
+    .map_err(|e| Problem::new(Status::UnprocessableEntity, "invalid-message", e))?;
+    check_recipients(raw.envelope.to(), recipient_policy, unsubscribe)?;
+
+    mailer.sign_raw(&mut raw);
+    match queue
+        .enqueue_raw(&raw.envelope, raw.formatted, raw.message_id, send_at)
+        .await
+    {
+        Ok(id) => Ok((Status::Accepted, id.to_string())),
+        Err(e) => Err((Status::InternalServerError, e.to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::blocking::Client;
+    use std::fs;
+    use std::time::Duration;
+
+    fn test_config() -> queue::QueueConfig {
+        queue::QueueConfig {
+            dir: std::env::temp_dir().join(format!("rest2smtp-main-{}", uuid::Uuid::new_v4())),
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(30),
+            retry_max_delay: Duration::from_secs(300),
+            retention: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn rejects_requests_without_the_api_token() {
+        let config = test_config();
+        let rocket = rocket::build()
+            .manage(ApiTokenConfig {
+                token: Some("secret".into()),
+            })
+            .manage(queue::Queue::open(config.clone()).unwrap())
+            .mount("/", routes![message_status])
+            .register("/", catchers![problem_catcher]);
+        let client = Client::untracked(rocket).unwrap();
+        let uri = format!("/messages/{}", uuid::Uuid::new_v4());
+
+        let response = client.get(&uri).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some(r#"Bearer realm="api""#)
+        );
+        let response = client
+            .get(&uri)
+            .header(Header::new("Authorization", "Bearer wrong"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get(&uri)
+            .header(Header::new("Authorization", "Bearer secret"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn one_click_unsubscribe_needs_the_rfc_8058_body() {
+        let dir = std::env::temp_dir().join(format!("rest2smtp-main-{}", uuid::Uuid::new_v4()));
+        let unsubscribe = unsubscribe::Unsubscribe::open(dir.join("unsubscribed.txt"))
+            .unwrap()
+            .with_one_click("https://mail.example.org".into(), "secret");
+        let jane: [Address; 1] = ["jane@example.org".parse().unwrap()];
+        let mut headers = lettre::message::header::Headers::new();
+        for header in unsubscribe.headers(None, true, &jane).unwrap() {
+            headers.insert_raw(header);
         }
-        Err(e) => (Status::UnprocessableEntity, format!("{}", e)),
+        let uri = headers
+            .get_raw("List-Unsubscribe")
+            .unwrap()
+            .trim_start_matches("<https://mail.example.org")
+            .trim_end_matches('>')
+            .to_string();
+        let rocket = rocket::build()
+            .manage(unsubscribe)
+            .mount("/", routes![unsubscribe_one_click])
+            .register("/", catchers![problem_catcher]);
+        let client = Client::untracked(rocket).unwrap();
+
+        for body in ["", "List-Unsubscribe=Other"] {
+            let response = client
+                .post(&uri)
+                .header(ContentType::Form)
+                .body(body)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+        }
+        let unsubscribe = client.rocket().state::<unsubscribe::Unsubscribe>().unwrap();
+        assert!(unsubscribe.unsubscribed(&jane).is_empty());
+        let response = client
+            .post(&uri)
+            .header(ContentType::Form)
+            .body("List-Unsubscribe=One-Click")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(unsubscribe.unsubscribed(&jane), ["jane@example.org"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // needs a runtime, the mailer starts its connection pool
+    async fn raw_client(
+        config: &queue::QueueConfig,
+        unsubscribe: unsubscribe::Unsubscribe,
+    ) -> rocket::local::asynchronous::Client {
+        let mailer = mailer::Mailer::new(config::SmtpConfig {
+            host: "localhost".into(),
+            port: None,
+            encryption: config::SmtpEncryption::Unencrypted,
+            username: None,
+            password: None,
+            dkim: Vec::new(),
+            dkim_headers: Vec::new(),
+        });
+        let rocket = rocket::build()
+            .manage(queue::Queue::open(config.clone()).unwrap())
+            .manage(mailer)
+            .manage(recipients::RecipientPolicy::default())
+            .manage(unsubscribe)
+            .mount("/", routes![sendmail_raw])
+            .register("/", catchers![problem_catcher]);
+        rocket::local::asynchronous::Client::untracked(rocket)
+            .await
+            .unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn accepts_raw_messages_with_sender_and_dkim_headers() {
+        let config = test_config();
+        let unsubscribe =
+            unsubscribe::Unsubscribe::open(config.dir.join("unsubscribed.txt")).unwrap();
+        let client = raw_client(&config, unsubscribe).await;
+        let response = client
+            .post("/send/raw")
+            .header(ContentType::new("message", "rfc822"))
+            .body(
+                "From: Newsletter <news@example.org>\r\n\
+                 Sender: bounces@example.org\r\n\
+                 DKIM-Signature: v=1; a=rsa-sha256; d=example.org; s=mail; b=abc\r\n\
+                 To: jane@example.org\r\n\
+                 Subject: Hi\r\n\
+                 \r\n\
+                 Hello\r\n",
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Accepted);
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn refuses_raw_messages_to_unsubscribed_recipients() {
+        let config = test_config();
+        let unsubscribe =
+            unsubscribe::Unsubscribe::open(config.dir.join("unsubscribed.txt")).unwrap();
+        unsubscribe.record("Jane@example.org").unwrap();
+        let client = raw_client(&config, unsubscribe).await;
+        let response = client
+            .post("/send/raw")
+            .header(ContentType::new("message", "rfc822"))
+            .body(
+                "From: news@example.org\r\n\
+                 To: jane@example.org\r\n\
+                 Subject: Hi\r\n\
+                 \r\n\
+                 Hello\r\n",
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let problem: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(problem["type"], "urn:rest2smtp:recipient-unsubscribed");
+        assert_eq!(
+            problem["recipients"],
+            serde_json::json!(["jane@example.org"])
+        );
+
+        fs::remove_dir_all(&config.dir).unwrap();
     }
 }
@@ -0,0 +1,424 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use lettre::message::header::{ContentDisposition, ContentType};
+use lettre::message::{MultiPart, SinglePart};
+use lettre::Address;
+use pgp::composed::{
+    ArmorOptions, Deserializable, DetachedSignature, MessageBuilder, SignedPublicKey,
+    SignedPublicSubKey, SignedSecretKey,
+};
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::packet::{KeyFlags, Signature, SignatureType};
+use pgp::types::{KeyDetails, Password, SigningKey, Timestamp};
+use rand::thread_rng;
+
+#[derive(Debug, PartialEq)]
+pub enum PgpError {
+    /// signing was requested, but no signing key is configured
+    SigningNotConfigured,
+    /// encryption was requested, but no keyring directory is configured
+    EncryptionNotConfigured,
+    /// no valid encryption key for the recipient address in the keyring
+    MissingKey(String),
+    Crypto(String),
+}
+
+impl fmt::Display for PgpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PgpError::SigningNotConfigured => write!(f, "OpenPGP signing is not configured"),
+            PgpError::EncryptionNotConfigured => {
+                write!(f, "OpenPGP encryption is not configured")
+            }
+            PgpError::MissingKey(addr) => {
+                write!(f, "no valid OpenPGP key for recipient {}", addr)
+            }
+            PgpError::Crypto(e) => write!(f, "OpenPGP error: {}", e),
+        }
+    }
+}
+
+impl From<pgp::errors::Error> for PgpError {
+    fn from(e: pgp::errors::Error) -> Self {
+        PgpError::Crypto(e.to_string())
+    }
+}
+
+struct Signer {
+    key: SignedSecretKey,
+    /// index of the signing subkey, the primary key signs if there is none
+    subkey: Option<usize>,
+    passphrase: String,
+}
+
+/// Key of a recipient that mails are encrypted to.
+enum RecipientKey {
+    Primary(SignedPublicKey),
+    Subkey(SignedPublicSubKey),
+}
+
+/// OpenPGP settings: signing key from `PGP_SIGNING_KEY_FILE` (unlocked with
+/// `PGP_SIGNING_KEY_PASSPHRASE`), recipient public keys in `PGP_KEYRING_DIR`.
+pub struct OpenPgp {
+    signer: Option<Signer>,
+    pub keyring_dir: Option<PathBuf>,
+}
+
+impl OpenPgp {
+    pub fn from_env() -> Self {
+        let signer = env::var("PGP_SIGNING_KEY_FILE").ok().map(|key_file| {
+            let key = fs::read(&key_file)
+                .ok()
+                .and_then(|key| SignedSecretKey::from_reader_single(&key[..]).ok())
+                .map(|(key, _)| key)
+                .unwrap_or_else(|| panic!("cannot read OpenPGP signing key {}", key_file));
+            let passphrase = env::var("PGP_SIGNING_KEY_PASSPHRASE").unwrap_or_default();
+            let signer = Signer::new(key, passphrase)
+                .unwrap_or_else(|| panic!("OpenPGP key {} cannot sign", key_file));
+            // fail at startup instead of on the first signed mail
+            if let Err(e) = signer.sign(b"") {
+                panic!("cannot sign with OpenPGP key {}: {}", key_file, e);
+            }
+            signer
+        });
+        Self {
+            signer,
+            keyring_dir: env::var("PGP_KEYRING_DIR").ok().map(PathBuf::from),
+        }
+    }
+
+    pub fn can_sign(&self) -> bool {
+        self.signer.is_some()
+    }
+
+    /// Signs and/or encrypts `body` as PGP/MIME (RFC 3156). With `encrypt_to` the result
+    /// can only be read by these recipients, so all of them need a key in the keyring.
+    pub fn protect(
+        &self,
+        body: MultiPart,
+        sign: bool,
+        encrypt_to: Option<&[Address]>,
+    ) -> Result<MultiPart, PgpError> {
+        let signer = match sign {
+            true => Some(self.signer.as_ref().ok_or(PgpError::SigningNotConfigured)?),
+            false => None,
+        };
+        let Some(addrs) = encrypt_to else {
+            return match signer {
+                Some(signer) => signer.sign_multipart(body),
+                None => Ok(body),
+            };
+        };
+        let recipients = self.recipient_keys(addrs)?;
+
+        // signed and encrypted in one OpenPGP message (RFC 3156 section 6.2)
+        let mut builder = MessageBuilder::from_bytes("", body.formatted())
+            .seipd_v1(thread_rng(), SymmetricKeyAlgorithm::AES256);
+        if let Some(signer) = signer {
+            builder.sign(
+                signer.signing_key(),
+                signer.passphrase.as_str().into(),
+                HashAlgorithm::Sha256,
+            );
+        }
+        for recipient in &recipients {
+            match recipient {
+                RecipientKey::Primary(key) => builder.encrypt_to_key(thread_rng(), key)?,
+                RecipientKey::Subkey(key) => builder.encrypt_to_key(thread_rng(), key)?,
+            };
+        }
+        let encrypted = builder.to_armored_string(thread_rng(), ArmorOptions::default())?;
+
+        Ok(MultiPart::encrypted("application/pgp-encrypted".into())
+            .singlepart(
+                SinglePart::builder()
+                    .header(ContentType::parse("application/pgp-encrypted").unwrap())
+                    .body("Version: 1".to_string()),
+            )
+            .singlepart(
+                SinglePart::builder()
+                    .header(
+                        ContentType::parse("application/octet-stream; name=encrypted.asc").unwrap(),
+                    )
+                    .header(ContentDisposition::inline_with_name("encrypted.asc"))
+                    .body(encrypted),
+            ))
+    }
+
+    // the keyring is read per request, so keys can be added without a restart
+    fn recipient_keys(&self, addrs: &[Address]) -> Result<Vec<RecipientKey>, PgpError> {
+        let dir = self
+            .keyring_dir
+            .as_ref()
+            .ok_or(PgpError::EncryptionNotConfigured)?;
+        let keys: Vec<SignedPublicKey> = fs::read_dir(dir)
+            .map_err(|e| PgpError::Crypto(format!("cannot read keyring: {}", e)))?
+            .filter_map(|entry| fs::read(entry.ok()?.path()).ok())
+            .filter_map(|data| {
+                let (keys, _) = SignedPublicKey::from_reader_many(&data[..]).ok()?;
+                Some(keys.filter_map(Result::ok).collect::<Vec<_>>())
+            })
+            .flatten()
+            .collect();
+
+        addrs
+            .iter()
+            .map(|addr| {
+                keys.iter()
+                    .filter(|key| has_user(key, addr))
+                    .find_map(encryption_key)
+                    .ok_or_else(|| PgpError::MissingKey(addr.to_string().to_lowercase()))
+            })
+            .collect()
+    }
+}
+
+impl Signer {
+    fn new(key: SignedSecretKey, passphrase: String) -> Option<Self> {
+        let now = Timestamp::now();
+        let subkey = key.secret_subkeys.iter().position(|subkey| {
+            latest(&subkey.signatures).is_some_and(|sig| {
+                sig.typ() == Some(SignatureType::SubkeyBinding)
+                    && sig.key_flags().sign()
+                    && !expired(subkey.key.created_at(), sig, now)
+            })
+        });
+        if subkey.is_none() && !primary_flags(&key.to_public_key()).sign() {
+            return None;
+        }
+        Some(Signer {
+            key,
+            subkey,
+            passphrase,
+        })
+    }
+
+    fn signing_key(&self) -> &dyn SigningKey {
+        match self.subkey {
+            Some(i) => &self.key.secret_subkeys[i].key,
+            None => &self.key.primary_key,
+        }
+    }
+
+    fn sign(&self, content: &[u8]) -> Result<String, PgpError> {
+        let signature = DetachedSignature::sign_binary_data(
+            thread_rng(),
+            &Box::new(self.signing_key()),
+            &Password::from(self.passphrase.as_str()),
+            HashAlgorithm::Sha256,
+            content,
+        )?;
+        Ok(signature.to_armored_string(ArmorOptions::default())?)
+    }
+
+    fn sign_multipart(&self, body: MultiPart) -> Result<MultiPart, PgpError> {
+        // the line break before the next boundary belongs to the boundary, not the signed part
+        let content = body.formatted();
+        let signature = self.sign(content.strip_suffix(b"\r\n").unwrap_or(&content))?;
+        Ok(
+            MultiPart::signed("application/pgp-signature".into(), "pgp-sha256".into())
+                .multipart(body)
+                .singlepart(
+                    SinglePart::builder()
+                        .header(
+                            ContentType::parse("application/pgp-signature; name=signature.asc")
+                                .unwrap(),
+                        )
+                        .header(ContentDisposition::attachment("signature.asc"))
+                        .body(signature),
+                ),
+        )
+    }
+}
+
+// user IDs look like "Jane Doe <jane@example.org>" or just the address
+fn has_user(key: &SignedPublicKey, addr: &Address) -> bool {
+    let addr = addr.to_string();
+    key.details.users.iter().any(|user| {
+        let Some(id) = user.id.as_str() else {
+            return false;
+        };
+        let email = match (id.rfind('<'), id.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &id[start + 1..end],
+            _ => id,
+        };
+        email.trim().eq_ignore_ascii_case(&addr)
+    })
+}
+
+/// Picks a valid encryption subkey, or the primary key if it can encrypt itself.
+fn encryption_key(key: &SignedPublicKey) -> Option<RecipientKey> {
+    let now = Timestamp::now();
+    if !key.details.revocation_signatures.is_empty() || key.verify_bindings().is_err() {
+        return None;
+    }
+    let primary_valid = key
+        .details
+        .users
+        .iter()
+        .filter_map(|user| latest(&user.signatures))
+        .all(|sig| !expired(key.primary_key.created_at(), sig, now));
+    if !primary_valid {
+        return None;
+    }
+
+    let subkey = key.public_subkeys.iter().rev().find(|subkey| {
+        latest(&subkey.signatures).is_some_and(|sig| {
+            let flags = sig.key_flags();
+            sig.typ() == Some(SignatureType::SubkeyBinding)
+                && (flags.encrypt_comms() || flags.encrypt_storage())
+                && subkey.key.algorithm().can_encrypt()
+                && !expired(subkey.key.created_at(), sig, now)
+        })
+    });
+    match subkey {
+        Some(subkey) => Some(RecipientKey::Subkey(subkey.clone())),
+        None => {
+            let flags = primary_flags(key);
+            ((flags.encrypt_comms() || flags.encrypt_storage())
+                && key.primary_key.algorithm().can_encrypt())
+            .then(|| RecipientKey::Primary(key.clone()))
+        }
+    }
+}
+
+fn primary_flags(key: &SignedPublicKey) -> KeyFlags {
+    key.details
+        .users
+        .iter()
+        .filter_map(|user| latest(&user.signatures))
+        .chain(latest(&key.details.direct_signatures))
+        .map(Signature::key_flags)
+        .next()
+        .unwrap_or_default()
+}
+
+fn latest(signatures: &[Signature]) -> Option<&Signature> {
+    signatures.iter().max_by_key(|sig| sig.created())
+}
+
+fn expired(created_at: Timestamp, sig: &Signature, now: Timestamp) -> bool {
+    sig.key_expiration_time().is_some_and(|validity| {
+        u64::from(created_at.as_secs()) + u64::from(validity.as_secs()) <= u64::from(now.as_secs())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pgp::composed::{
+        EncryptionCaps, KeyType, Message, SecretKeyParamsBuilder, SubkeyParamsBuilder,
+    };
+    use pgp::crypto::ecc_curve::ECCCurve;
+
+    fn generate_key(user_id: &str) -> SignedSecretKey {
+        let mut subkey = SubkeyParamsBuilder::default();
+        subkey
+            .key_type(KeyType::ECDH(ECCCurve::Curve25519Legacy))
+            .can_encrypt(EncryptionCaps::All);
+        let mut params = SecretKeyParamsBuilder::default();
+        params
+            .key_type(KeyType::Ed25519Legacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id(user_id.into())
+            .subkeys(vec![subkey.build().unwrap()]);
+        params.build().unwrap().generate(thread_rng()).unwrap()
+    }
+
+    fn test_body() -> MultiPart {
+        MultiPart::alternative_plain_html("Hello".to_string(), "<p>Hello</p>".to_string())
+    }
+
+    fn armored(formatted: &[u8], label: &str) -> String {
+        let formatted = String::from_utf8_lossy(formatted);
+        let start = formatted
+            .find(&format!("-----BEGIN PGP {}-----", label))
+            .unwrap();
+        let end_marker = format!("-----END PGP {}-----", label);
+        let end = formatted.find(&end_marker).unwrap() + end_marker.len();
+        formatted[start..end].to_string()
+    }
+
+    #[test]
+    fn signs_with_detached_signature() {
+        let key = generate_key("Sender <sender@example.org>");
+        let public = key.to_public_key();
+        let openpgp = OpenPgp {
+            signer: Signer::new(key, String::new()),
+            keyring_dir: None,
+        };
+
+        let body = test_body();
+        let content = body.formatted();
+        let signed = openpgp.protect(body, true, None).unwrap().formatted();
+        let signed_text = String::from_utf8_lossy(&signed);
+        assert!(signed_text.contains("protocol=\"application/pgp-signature\""));
+        assert!(signed_text.contains("micalg=\"pgp-sha256\""));
+
+        let (signature, _) =
+            DetachedSignature::from_string(&armored(&signed, "SIGNATURE")).unwrap();
+        signature
+            .verify(&public, content.strip_suffix(b"\r\n").unwrap())
+            .unwrap();
+        assert!(signature.verify(&public, &content).is_err());
+    }
+
+    #[test]
+    fn encrypts_to_keys_from_keyring() {
+        let dir = env::temp_dir().join(format!("rest2smtp-pgp-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let rcpt_key = generate_key("Rcpt <rcpt@example.org>");
+        fs::write(
+            dir.join("rcpt.asc"),
+            rcpt_key
+                .to_public_key()
+                .to_armored_string(ArmorOptions::default())
+                .unwrap(),
+        )
+        .unwrap();
+        let sender_key = generate_key("sender@example.org");
+        let sender_public = sender_key.to_public_key();
+        let openpgp = OpenPgp {
+            signer: Signer::new(sender_key, String::new()),
+            keyring_dir: Some(dir.clone()),
+        };
+
+        let rcpt: Address = "Rcpt@Example.org".parse().unwrap();
+        let encrypted = openpgp
+            .protect(test_body(), true, Some(&[rcpt]))
+            .unwrap()
+            .formatted();
+        assert!(
+            String::from_utf8_lossy(&encrypted).contains("protocol=\"application/pgp-encrypted\"")
+        );
+
+        let armored_message = armored(&encrypted, "MESSAGE");
+        let (message, _) = Message::from_string(&armored_message).unwrap();
+        let mut message = message.decrypt(&Password::empty(), &rcpt_key).unwrap();
+        let content = message.as_data_string().unwrap();
+        assert!(content.starts_with("Content-Type: multipart/alternative;"));
+        assert!(content.contains("<p>Hello</p>"));
+        message.verify(&sender_public).unwrap();
+
+        let other: Address = "other@example.org".parse().unwrap();
+        assert_eq!(
+            openpgp.protect(test_body(), false, Some(&[other])).err(),
+            Some(PgpError::MissingKey("other@example.org".into()))
+        );
+        let openpgp = OpenPgp {
+            signer: None,
+            keyring_dir: Some(dir.clone()),
+        };
+        assert_eq!(
+            openpgp.protect(test_body(), true, None).err(),
+            Some(PgpError::SigningNotConfigured)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use std::env;
+
+/// Generation of the plain text alternative for mails with only HTML content. On by
+/// default, `HTML_TO_TEXT=false` turns it off unless requested per mail.
+pub struct PlainText {
+    pub enabled: bool,
+    /// line width of the generated text, from `HTML_TO_TEXT_WIDTH`
+    pub width: usize,
+}
+
+impl PlainText {
+    pub fn from_env() -> Self {
+        let enabled = match env::var("HTML_TO_TEXT").ok().as_deref().map(str::trim) {
+            None | Some("true") => true,
+            Some("false") => false,
+            Some(_) => panic!("HTML_TO_TEXT must be true or false"),
+        };
+        let width = env::var("HTML_TO_TEXT_WIDTH").ok().map(|v| {
+            v.trim()
+                .parse()
+                .unwrap_or_else(|_| panic!("HTML_TO_TEXT_WIDTH is not a number"))
+        });
+        Self {
+            enabled,
+            width: width.unwrap_or(78),
+        }
+    }
+
+    /// Text alternative for `html`, if `requested` (falling back to the configured
+    /// default) and `text` is not already given.
+    pub fn alternative(
+        &self,
+        text: Option<String>,
+        html: Option<&str>,
+        requested: Option<bool>,
+    ) -> Option<String> {
+        match (text, html) {
+            (Some(text), _) => Some(text),
+            (None, Some(html)) if requested.unwrap_or(self.enabled) => self.render(html),
+            (None, _) => None,
+        }
+    }
+
+    // links become numbered footnotes, lists and tables are laid out in text
+    fn render(&self, html: &str) -> Option<String> {
+        html2text::config::plain()
+            .allow_width_overflow()
+            .string_from_read(html.as_bytes(), self.width)
+            .ok()
+            .map(|text| text.trim_end().to_string() + "\n")
+            .filter(|text| !text.trim().is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain_text(enabled: bool) -> PlainText {
+        PlainText { enabled, width: 78 }
+    }
+
+    #[test]
+    fn renders_links_lists_and_tables() {
+        let html = r#"<h1>Invoice</h1>
+            <p>Hello <b>Jane</b>, see <a href="https://example.org/invoice/1">your invoice</a>.</p>
+            <ul><li>First</li><li>Second</li></ul>
+            <table><tr><th>Item</th><th>Price</th></tr><tr><td>Tea</td><td>3.50</td></tr></table>"#;
+        let text = plain_text(true)
+            .alternative(None, Some(html), None)
+            .unwrap();
+        assert!(text.contains("see [your invoice][1]."));
+        assert!(text.contains("[1]: https://example.org/invoice/1"));
+        assert!(text.contains("* First\n* Second"));
+        assert!(text.contains("Item│Price"));
+        assert!(text.contains("Tea │3.50"));
+        assert!(!text.contains('<'));
+    }
+
+    #[test]
+    fn respects_given_text_and_settings() {
+        let html = Some("<p>Hi</p>");
+        assert_eq!(
+            plain_text(true).alternative(Some("Own".into()), html, None),
+            Some("Own".into())
+        );
+        assert_eq!(plain_text(false).alternative(None, html, None), None);
+        assert_eq!(
+            plain_text(false).alternative(None, html, Some(true)),
+            Some("Hi\n".into())
+        );
+        assert_eq!(plain_text(true).alternative(None, html, Some(false)), None);
+        assert_eq!(plain_text(true).alternative(None, None, None), None);
+    }
+}
@@ -0,0 +1,252 @@
+use std::io::{self, Cursor};
+
+use rocket::form;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{self, serde_json};
+use rocket::serde::{DeserializeOwned, Serialize};
+
+/// Error response as RFC 9457 `application/problem+json`. `type` is a stable
+/// `urn:rest2smtp:<code>` clients can match on, `errors` names the offending fields.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: &'static str,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    /// recipients the request was refused for
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+}
+
+/// Problem with a single request field, e.g. `to_addresses` or `event.start`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl Problem {
+    pub fn new(status: Status, code: &str, detail: impl Into<String>) -> Self {
+        Self {
+            kind: format!("urn:rest2smtp:{}", code),
+            title: status.reason_lossy(),
+            status: status.code,
+            detail: Some(detail.into()),
+            errors: Vec::new(),
+            recipients: Vec::new(),
+        }
+    }
+
+    /// `403` for recipients the mail must not be sent to.
+    pub fn forbidden_recipients(code: &str, reason: &str, recipients: Vec<String>) -> Self {
+        let detail = format!("{}: {}", reason, recipients.join(", "));
+        Self {
+            recipients,
+            ..Self::new(Status::Forbidden, code, detail)
+        }
+    }
+
+    /// Generic problem for a status, without a more specific code.
+    pub fn status(status: Status) -> Self {
+        let code = match status.code {
+            400 => "bad-request",
+            401 => "unauthorized",
+            403 => "forbidden",
+            404 => "not-found",
+            409 => "conflict",
+            413 => "payload-too-large",
+            422 => "unprocessable",
+            500..=599 => "server-error",
+            _ => "error",
+        };
+        Self {
+            detail: None,
+            ..Self::new(status, code, "")
+        }
+    }
+
+    /// `422` for an invalid request field.
+    pub fn invalid(field: &str, message: impl Into<String>) -> Self {
+        Self::invalid_fields(vec![FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }])
+    }
+
+    pub fn invalid_fields(errors: Vec<FieldError>) -> Self {
+        let detail = match &errors[..] {
+            [error] => format!("{}: {}", error.field, error.message),
+            _ => format!("{} invalid fields", errors.len()),
+        };
+        Self {
+            errors,
+            ..Self::new(Status::UnprocessableEntity, "invalid-field", detail)
+        }
+    }
+
+    /// Errors of a form that could not be parsed, e.g. a missing `to_address`.
+    pub fn from_form(errors: &form::Errors<'_>) -> Self {
+        if errors.status() == Status::PayloadTooLarge {
+            return (Status::PayloadTooLarge, errors.to_string()).into();
+        }
+        Self::invalid_fields(
+            errors
+                .iter()
+                .map(|error| FieldError {
+                    field: error
+                        .name
+                        .as_ref()
+                        .map(|name| name.to_string())
+                        .unwrap_or_default(),
+                    message: error.kind.to_string(),
+                })
+                .collect(),
+        )
+    }
+
+    /// A JSON body that could not be read or parsed.
+    pub fn from_json(error: &json::Error<'_>) -> Self {
+        match error {
+            json::Error::Parse(_, e) => {
+                Self::new(Status::UnprocessableEntity, "malformed-json", e.to_string())
+            }
+            json::Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                (Status::PayloadTooLarge, e.to_string()).into()
+            }
+            json::Error::Io(e) => (Status::BadRequest, e.to_string()).into(),
+        }
+    }
+
+    /// Deserializes a JSON body, errors name the path of the offending value like
+    /// `event.start` or `attachments[0].content`.
+    pub fn parse_json<T: DeserializeOwned>(value: serde_json::Value) -> Result<T, Self> {
+        serde_path_to_error::deserialize(value).map_err(|e| Self::from_serde(&e))
+    }
+
+    /// JSON that does not match the schema. Missing fields are reported at their parent,
+    /// serde names them in the message.
+    pub fn from_serde(error: &serde_path_to_error::Error<serde_json::Error>) -> Self {
+        let detail = error.inner().to_string();
+        let named = detail
+            .split_once('`')
+            .and_then(|(prefix, rest)| prefix.ends_with("field ").then_some(rest))
+            .and_then(|rest| rest.split_once('`'))
+            .map(|(field, _)| field);
+        let path = error.path().to_string();
+        let field = match named {
+            _ if path == "." => named.map(str::to_string),
+            Some(name) if path != name && !path.ends_with(&format!(".{}", name)) => {
+                Some(format!("{}.{}", path, name))
+            }
+            _ => Some(path),
+        };
+        match field {
+            Some(field) => Self::invalid(&field, detail),
+            None => Self::new(Status::UnprocessableEntity, "malformed-json", detail),
+        }
+    }
+}
+
+impl From<(Status, String)> for Problem {
+    fn from((status, detail): (Status, String)) -> Self {
+        Problem {
+            detail: Some(detail),
+            ..Problem::status(status)
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Problem {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let body = serde_json::to_string(&self).map_err(|_| Status::InternalServerError)?;
+        let mut response = Response::build();
+        response
+            .status(Status::from_code(self.status).unwrap_or(Status::InternalServerError))
+            .header(ContentType::new("application", "problem+json"))
+            .sized_body(body.len(), Cursor::new(body));
+        if self.status == Status::Unauthorized.code {
+            response.header(Header::new("WWW-Authenticate", r#"Bearer realm="api""#));
+        }
+        Ok(response.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_field_errors() {
+        let problem = Problem::invalid("to_addresses", "missing or empty");
+        assert_eq!(
+            serde_json::to_value(&problem).unwrap(),
+            serde_json::json!({
+                "type": "urn:rest2smtp:invalid-field",
+                "title": "Unprocessable Entity",
+                "status": 422,
+                "detail": "to_addresses: missing or empty",
+                "errors": [{"field": "to_addresses", "message": "missing or empty"}]
+            })
+        );
+    }
+
+    #[test]
+    fn maps_statuses_to_codes() {
+        let problem = Problem::from((Status::Conflict, "busy".to_string()));
+        assert_eq!(problem.kind, "urn:rest2smtp:conflict");
+        assert_eq!(problem.status, 409);
+        assert_eq!(problem.detail.as_deref(), Some("busy"));
+        assert!(problem.errors.is_empty());
+        assert_eq!(
+            Problem::from((Status::BadGateway, String::new())).kind,
+            "urn:rest2smtp:server-error"
+        );
+    }
+
+    #[test]
+    fn names_fields_of_json_errors() {
+        #[derive(Debug, rocket::serde::Deserialize)]
+        #[serde(crate = "rocket::serde", deny_unknown_fields)]
+        #[allow(dead_code)]
+        struct Mail {
+            subject: String,
+            attachments: Option<Vec<Attachment>>,
+        }
+        #[derive(Debug, rocket::serde::Deserialize)]
+        #[serde(crate = "rocket::serde")]
+        #[allow(dead_code)]
+        struct Attachment {
+            filename: String,
+        }
+        let field = |value| {
+            Problem::parse_json::<Mail>(value).unwrap_err().errors[0]
+                .field
+                .clone()
+        };
+        assert_eq!(field(serde_json::json!({})), "subject");
+        assert_eq!(field(serde_json::json!({"subject": 5})), "subject");
+        assert_eq!(field(serde_json::json!({"subjekt": ""})), "subjekt");
+        assert_eq!(
+            field(serde_json::json!({"subject": "", "attachments": [{"filename": 1}]})),
+            "attachments[0].filename"
+        );
+        assert_eq!(
+            field(serde_json::json!({"subject": "", "attachments": [{}]})),
+            "attachments[0].filename"
+        );
+        assert_eq!(
+            Problem::parse_json::<Mail>(serde_json::json!([]))
+                .unwrap_err()
+                .kind,
+            "urn:rest2smtp:malformed-json"
+        );
+    }
+}
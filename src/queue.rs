@@ -0,0 +1,631 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lettre::address::Envelope;
+use lettre::transport::smtp::response::Response;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rocket::futures::{stream, StreamExt};
+use rocket::serde::{json::serde_json, Deserialize, Serialize};
+use rocket::tokio::{self, sync::Notify};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+/// Number of queued messages handed to the SMTP pool at the same time.
+const DELIVERY_CONCURRENCY: usize = 4;
+
+/// Upper bound for the worker to sleep when nothing is due.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Outbound queue settings loaded from `QUEUE_*` env vars.
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    pub dir: PathBuf,
+    pub max_attempts: u32,
+    pub retry_delay: Duration,
+    pub retry_max_delay: Duration,
+    /// How long records of sent or failed messages stay queryable.
+    pub retention: Duration,
+}
+
+impl QueueConfig {
+    pub fn from_env() -> Self {
+        Self {
+            dir: env::var("QUEUE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from("queue")),
+            max_attempts: env_number("QUEUE_MAX_ATTEMPTS").unwrap_or(10).max(1),
+            retry_delay: Duration::from_secs(env_number("QUEUE_RETRY_DELAY").unwrap_or(60)),
+            retry_max_delay: Duration::from_secs(
+                env_number("QUEUE_RETRY_MAX_DELAY").unwrap_or(3600),
+            ),
+            retention: Duration::from_secs(env_number("QUEUE_RETENTION").unwrap_or(7 * 24 * 3600)),
+        }
+    }
+
+    /// Exponential backoff: `retry_delay * 2^(attempts-1)`, capped at `retry_max_delay`.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.retry_delay
+            .saturating_mul(factor)
+            .min(self.retry_max_delay)
+    }
+}
+
+fn env_number<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().map(|v| {
+        v.trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is not a number", name))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum MessageStatus {
+    Queued,
+    Sending,
+    Sent,
+    Deferred,
+    Failed,
+    Cancelled,
+}
+
+impl MessageStatus {
+    fn is_final(self) -> bool {
+        matches!(
+            self,
+            MessageStatus::Sent | MessageStatus::Failed | MessageStatus::Cancelled
+        )
+    }
+}
+
+/// Reasons a message cannot be cancelled.
+#[derive(Debug, PartialEq)]
+pub enum CancelError {
+    UnknownMessage,
+    NotPending(MessageStatus),
+}
+
+/// One delivery attempt with the SMTP reply, if the server sent one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DeliveryAttempt {
+    #[serde(with = "time::serde::rfc3339")]
+    pub started_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub finished_at: OffsetDateTime,
+    pub smtp_code: Option<u16>,
+    /// RFC 3463 status like `2.0.0`, if the reply carries one
+    #[serde(default)]
+    pub enhanced_status: Option<String>,
+    pub smtp_reply: String,
+}
+
+/// Delivery state of an accepted message, persisted as `<id>.json`.
+/// The rendered message lives next to it as `<id>.eml` until it is sent or failed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MessageRecord {
+    pub id: Uuid,
+    pub status: MessageStatus,
+    /// `Message-ID` header of the message, for correlation with bounces and logs
+    #[serde(default)]
+    pub message_id: Option<String>,
+    pub envelope_from: Option<String>,
+    pub envelope_to: Vec<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    /// Requested delivery time, unset for immediate delivery.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub send_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub next_attempt_at: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub cancelled_at: Option<OffsetDateTime>,
+    pub attempts: Vec<DeliveryAttempt>,
+}
+
+impl MessageRecord {
+    fn envelope(&self) -> Result<Envelope, String> {
+        let from = match &self.envelope_from {
+            Some(addr) => Some(addr.parse::<Address>().map_err(|e| e.to_string())?),
+            None => None,
+        };
+        let to = self
+            .envelope_to
+            .iter()
+            .map(|addr| addr.parse::<Address>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        Envelope::new(from, to).map_err(|e| e.to_string())
+    }
+
+    fn is_due(&self, now: OffsetDateTime) -> bool {
+        matches!(self.status, MessageStatus::Queued | MessageStatus::Deferred)
+            && self.next_attempt_at.is_some_and(|at| at <= now)
+    }
+
+    fn finished_at(&self) -> OffsetDateTime {
+        self.cancelled_at
+            .or(self.attempts.last().map(|attempt| attempt.finished_at))
+            .unwrap_or(self.created_at)
+    }
+}
+
+/// Persistent outbound queue. Accepted messages are written to `QUEUE_DIR` and
+/// delivered by a background worker (see [`Queue::run`]), so they survive restarts.
+#[derive(Clone)]
+pub struct Queue {
+    config: Arc<QueueConfig>,
+    records: Arc<Mutex<HashMap<Uuid, MessageRecord>>>,
+    wakeup: Arc<Notify>,
+}
+
+impl Queue {
+    /// Opens the queue directory and loads all records left over from a previous run.
+    pub fn open(config: QueueConfig) -> io::Result<Queue> {
+        fs::create_dir_all(&config.dir)?;
+
+        let mut records = HashMap::new();
+        for dir_entry in fs::read_dir(&config.dir)? {
+            let path = dir_entry?.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+            let mut record: MessageRecord = match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(record) => record,
+                Err(e) => {
+                    eprintln!("Skipping unreadable queue record {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if !record.status.is_final() && !message_path(&config.dir, &record.id).exists() {
+                eprintln!("Skipping queue record {} without message file", record.id);
+                continue;
+            }
+            if record.status == MessageStatus::Sending {
+                // the process stopped mid-delivery, so the attempt never finished
+                record.status = MessageStatus::Queued;
+            }
+            records.insert(record.id, record);
+        }
+
+        Ok(Queue {
+            config: Arc::new(config),
+            records: Arc::new(Mutex::new(records)),
+            wakeup: Arc::new(Notify::new()),
+        })
+    }
+
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    /// Number of messages waiting for delivery.
+    pub fn pending(&self) -> usize {
+        self.records
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|record| !record.status.is_final())
+            .count()
+    }
+
+    pub fn status(&self, id: &Uuid) -> Option<MessageRecord> {
+        self.records.lock().unwrap().get(id).cloned()
+    }
+
+    /// Stores the message on disk and schedules it for delivery at `send_at`,
+    /// or immediately when no time (or one in the past) is given.
+    pub async fn enqueue(
+        &self,
+        mail: Message,
+        send_at: Option<OffsetDateTime>,
+    ) -> io::Result<Uuid> {
+        let message_id = mail.headers().get_raw("Message-ID").map(str::to_string);
+        self.enqueue_raw(mail.envelope(), mail.formatted(), message_id, send_at)
+            .await
+    }
+
+    /// Like [`Queue::enqueue`], for messages that are already formatted.
+    pub async fn enqueue_raw(
+        &self,
+        envelope: &Envelope,
+        message: Vec<u8>,
+        message_id: Option<String>,
+        send_at: Option<OffsetDateTime>,
+    ) -> io::Result<Uuid> {
+        let now = OffsetDateTime::now_utc();
+        let record = MessageRecord {
+            id: Uuid::new_v4(),
+            status: MessageStatus::Queued,
+            message_id,
+            envelope_from: envelope.from().map(|addr| addr.to_string()),
+            envelope_to: envelope.to().iter().map(|addr| addr.to_string()).collect(),
+            created_at: now,
+            send_at,
+            next_attempt_at: Some(send_at.map_or(now, |at| at.max(now))),
+            cancelled_at: None,
+            attempts: Vec::new(),
+        };
+
+        tokio::fs::write(message_path(&self.config.dir, &record.id), message).await?;
+        self.persist(&record).await?;
+
+        let id = record.id;
+        self.records.lock().unwrap().insert(id, record);
+        self.wakeup.notify_one();
+        Ok(id)
+    }
+
+    /// Withdraws a message that is still waiting for (another) delivery attempt.
+    pub async fn cancel(&self, id: &Uuid) -> Result<MessageRecord, CancelError> {
+        let record = {
+            let mut records = self.records.lock().unwrap();
+            let record = records.get_mut(id).ok_or(CancelError::UnknownMessage)?;
+            if !matches!(
+                record.status,
+                MessageStatus::Queued | MessageStatus::Deferred
+            ) {
+                return Err(CancelError::NotPending(record.status));
+            }
+            record.status = MessageStatus::Cancelled;
+            record.next_attempt_at = None;
+            record.cancelled_at = Some(OffsetDateTime::now_utc());
+            record.clone()
+        };
+
+        println!("Message {} cancelled", record.id);
+        if let Err(e) = self.persist(&record).await {
+            eprintln!("Cannot update queue record {}: {}", record.id, e);
+        }
+        remove_file(&message_path(&self.config.dir, &record.id)).await;
+        Ok(record)
+    }
+
+    /// Delivery loop, to be spawned once at startup.
+    pub async fn run(self, transport: AsyncSmtpTransport<Tokio1Executor>) {
+        loop {
+            self.purge_expired().await;
+
+            let now = OffsetDateTime::now_utc();
+            let due: Vec<MessageRecord> = self
+                .records
+                .lock()
+                .unwrap()
+                .values_mut()
+                .filter(|record| record.is_due(now))
+                .map(|record| {
+                    record.status = MessageStatus::Sending;
+                    record.clone()
+                })
+                .collect();
+
+            stream::iter(due)
+                .for_each_concurrent(DELIVERY_CONCURRENCY, |record| {
+                    self.deliver(&transport, record)
+                })
+                .await;
+
+            let next_attempt_at = self
+                .records
+                .lock()
+                .unwrap()
+                .values()
+                .filter_map(|record| record.next_attempt_at)
+                .min();
+            let wait = match next_attempt_at {
+                Some(at) => Duration::try_from(at - OffsetDateTime::now_utc())
+                    .unwrap_or(Duration::ZERO)
+                    .min(IDLE_POLL_INTERVAL),
+                None => IDLE_POLL_INTERVAL,
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {},
+                _ = self.wakeup.notified() => {},
+            }
+        }
+    }
+
+    async fn deliver(
+        &self,
+        transport: &AsyncSmtpTransport<Tokio1Executor>,
+        mut record: MessageRecord,
+    ) {
+        let started_at = OffsetDateTime::now_utc();
+        let result = match (
+            record.envelope(),
+            tokio::fs::read(message_path(&self.config.dir, &record.id)).await,
+        ) {
+            (Ok(envelope), Ok(message)) => transport
+                .send_raw(&envelope, &message)
+                .await
+                .map_err(|e| (e.status().map(u16::from), e.to_string(), e.is_permanent())),
+            (Err(e), _) => Err((None, format!("invalid envelope: {}", e), true)),
+            (_, Err(e)) => Err((None, format!("message unreadable: {}", e), true)),
+        };
+        let finished_at = OffsetDateTime::now_utc();
+
+        match result {
+            Ok(response) => {
+                println!(
+                    "Message {} sent: {}",
+                    record.id,
+                    response.first_line().unwrap_or("")
+                );
+                record
+                    .attempts
+                    .push(attempt(started_at, finished_at, &response));
+                record.status = MessageStatus::Sent;
+                record.next_attempt_at = None;
+            }
+            Err((smtp_code, smtp_reply, permanent)) => {
+                record.attempts.push(DeliveryAttempt {
+                    started_at,
+                    finished_at,
+                    smtp_code,
+                    enhanced_status: enhanced_status(&smtp_reply),
+                    smtp_reply: smtp_reply.clone(),
+                });
+                let attempts = record.attempts.len() as u32;
+                if !permanent && attempts < self.config.max_attempts {
+                    let delay = self.config.backoff(attempts);
+                    println!(
+                        "Message {} deferred after attempt {}, retrying in {}s: {}",
+                        record.id,
+                        attempts,
+                        delay.as_secs(),
+                        smtp_reply
+                    );
+                    record.status = MessageStatus::Deferred;
+                    record.next_attempt_at = Some(finished_at + delay);
+                } else {
+                    eprintln!(
+                        "Message {} failed after {} attempt(s): {}",
+                        record.id, attempts, smtp_reply
+                    );
+                    record.status = MessageStatus::Failed;
+                    record.next_attempt_at = None;
+                }
+            }
+        }
+
+        if let Err(e) = self.persist(&record).await {
+            eprintln!("Cannot update queue record {}: {}", record.id, e);
+        }
+        if record.status.is_final() {
+            remove_file(&message_path(&self.config.dir, &record.id)).await;
+        }
+        self.records.lock().unwrap().insert(record.id, record);
+    }
+
+    /// Drops records of sent or failed messages once they are older than the retention period.
+    async fn purge_expired(&self) {
+        let now = OffsetDateTime::now_utc();
+        let expired: Vec<Uuid> = self
+            .records
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|record| {
+                record.status.is_final() && record.finished_at() + self.config.retention <= now
+            })
+            .map(|record| record.id)
+            .collect();
+
+        for id in expired {
+            self.records.lock().unwrap().remove(&id);
+            remove_file(&record_path(&self.config.dir, &id)).await;
+        }
+    }
+
+    /// Writes the record atomically so a crash never leaves a truncated file behind.
+    async fn persist(&self, record: &MessageRecord) -> io::Result<()> {
+        let path = record_path(&self.config.dir, &record.id);
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(record)?).await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+}
+
+fn attempt(
+    started_at: OffsetDateTime,
+    finished_at: OffsetDateTime,
+    response: &Response,
+) -> DeliveryAttempt {
+    DeliveryAttempt {
+        started_at,
+        finished_at,
+        smtp_code: Some(response.code().into()),
+        enhanced_status: response.first_line().and_then(enhanced_status),
+        smtp_reply: response.message().collect::<Vec<_>>().join("\n"),
+    }
+}
+
+// the first word of the reply text, e.g. "5.1.1" in "550 5.1.1 <a@b.c>: user unknown"
+fn enhanced_status(reply: &str) -> Option<String> {
+    reply
+        .split_whitespace()
+        .find(|word| {
+            let parts: Vec<&str> = word.split('.').collect();
+            matches!(parts[..], [class, _, _] if ["2", "4", "5"].contains(&class))
+                && parts[1..].iter().all(|part| {
+                    (1..=3).contains(&part.len()) && part.bytes().all(|b| b.is_ascii_digit())
+                })
+        })
+        .map(str::to_string)
+}
+
+async fn remove_file(path: &Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        eprintln!("Cannot remove {}: {}", path.display(), e);
+    }
+}
+
+fn record_path(dir: &Path, id: &Uuid) -> PathBuf {
+    dir.join(format!("{}.json", id))
+}
+
+fn message_path(dir: &Path, id: &Uuid) -> PathBuf {
+    dir.join(format!("{}.eml", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> QueueConfig {
+        QueueConfig {
+            dir: env::temp_dir().join(format!("rest2smtp-queue-{}", Uuid::new_v4())),
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(30),
+            retry_max_delay: Duration::from_secs(300),
+            retention: Duration::from_secs(3600),
+        }
+    }
+
+    fn test_message() -> Message {
+        Message::builder()
+            .from("sender@example.org".parse().unwrap())
+            .to("rcpt@example.org".parse().unwrap())
+            .bcc("hidden@example.org".parse().unwrap())
+            .subject("Test")
+            .body("Hi there".to_string())
+            .unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = test_config();
+        assert_eq!(config.backoff(1), Duration::from_secs(30));
+        assert_eq!(config.backoff(2), Duration::from_secs(60));
+        assert_eq!(config.backoff(4), Duration::from_secs(240));
+        assert_eq!(config.backoff(5), Duration::from_secs(300));
+        assert_eq!(config.backoff(100), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn extracts_enhanced_status_codes() {
+        assert_eq!(
+            enhanced_status("2.0.0 Ok: queued as 4Zx1").as_deref(),
+            Some("2.0.0")
+        );
+        assert_eq!(
+            enhanced_status("permanent error (550): 5.1.1 <a@example.org>: user unknown")
+                .as_deref(),
+            Some("5.1.1")
+        );
+        assert_eq!(enhanced_status("Ok: queued as 1.2.3456"), None);
+        assert_eq!(enhanced_status("network error: timed out"), None);
+    }
+
+    #[rocket::async_test]
+    async fn queued_messages_survive_reopening() {
+        let config = test_config();
+        let queue = Queue::open(config.clone()).unwrap();
+        let id = queue.enqueue(test_message(), None).await.unwrap();
+
+        let reopened = Queue::open(config.clone()).unwrap();
+        assert_eq!(reopened.pending(), 1);
+        let record = reopened.status(&id).unwrap();
+        assert_eq!(record.status, MessageStatus::Queued);
+        assert_eq!(record.envelope_from.as_deref(), Some("sender@example.org"));
+        assert_eq!(
+            record.envelope_to,
+            ["rcpt@example.org", "hidden@example.org"]
+        );
+        assert!(record.envelope().is_ok());
+
+        let message = fs::read_to_string(message_path(&config.dir, &id)).unwrap();
+        assert!(message.contains("Subject: Test"));
+        assert!(!message.contains("hidden@example.org"));
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn interrupted_deliveries_are_requeued_and_old_records_purged() {
+        let config = test_config();
+        let queue = Queue::open(config.clone()).unwrap();
+        let sending = queue.enqueue(test_message(), None).await.unwrap();
+        let sent = queue.enqueue(test_message(), None).await.unwrap();
+
+        let mut record = queue.status(&sending).unwrap();
+        record.status = MessageStatus::Sending;
+        queue.persist(&record).await.unwrap();
+
+        let mut record = queue.status(&sent).unwrap();
+        let long_ago = OffsetDateTime::now_utc() - Duration::from_secs(7200);
+        record.status = MessageStatus::Sent;
+        record.attempts.push(DeliveryAttempt {
+            started_at: long_ago,
+            finished_at: long_ago,
+            smtp_code: Some(250),
+            enhanced_status: enhanced_status("2.0.0 Ok"),
+            smtp_reply: "2.0.0 Ok".into(),
+        });
+        queue.persist(&record).await.unwrap();
+
+        let reopened = Queue::open(config.clone()).unwrap();
+        assert_eq!(reopened.pending(), 1);
+        assert_eq!(
+            reopened.status(&sending).unwrap().status,
+            MessageStatus::Queued
+        );
+
+        reopened.purge_expired().await;
+        assert!(reopened.status(&sent).is_none());
+        assert!(!record_path(&config.dir, &sent).exists());
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn scheduled_messages_wait_and_can_be_cancelled() {
+        let config = test_config();
+        let queue = Queue::open(config.clone()).unwrap();
+        let now = OffsetDateTime::now_utc();
+        let send_at = now + Duration::from_secs(3600);
+        let scheduled = queue.enqueue(test_message(), Some(send_at)).await.unwrap();
+        let overdue = queue
+            .enqueue(test_message(), Some(now - Duration::from_secs(60)))
+            .await
+            .unwrap();
+
+        let record = queue.status(&scheduled).unwrap();
+        assert_eq!(record.next_attempt_at, Some(send_at));
+        assert!(!record.is_due(now));
+        assert!(queue
+            .status(&overdue)
+            .unwrap()
+            .is_due(OffsetDateTime::now_utc()));
+
+        let record = queue.cancel(&scheduled).await.unwrap();
+        assert_eq!(record.status, MessageStatus::Cancelled);
+        assert!(!message_path(&config.dir, &scheduled).exists());
+        assert_eq!(
+            queue.cancel(&scheduled).await.unwrap_err(),
+            CancelError::NotPending(MessageStatus::Cancelled)
+        );
+        assert_eq!(
+            queue.cancel(&Uuid::new_v4()).await.unwrap_err(),
+            CancelError::UnknownMessage
+        );
+
+        let reopened = Queue::open(config.clone()).unwrap();
+        assert_eq!(reopened.pending(), 1);
+        assert_eq!(
+            reopened.status(&scheduled).unwrap().status,
+            MessageStatus::Cancelled
+        );
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+use lettre::address::Envelope;
+use lettre::message::header::{ContentTransferEncoding, HeaderName, HeaderValue, Headers};
+use lettre::message::{Body, Mailboxes};
+use lettre::{Address, Message};
+
+/// Complete RFC 5322 message submitted by the client, sent as is apart from
+/// line ending normalization and removal of the `Bcc` header.
+#[derive(Debug)]
+pub struct RawMessage {
+    pub envelope: Envelope,
+    pub formatted: Vec<u8>,
+    pub message_id: Option<String>,
+    /// unfolded header fields of `formatted`
+    fields: Vec<(String, String)>,
+    /// offset of the empty line between header and body in `formatted`
+    body_start: usize,
+}
+
+/// Splits off the header section and derives the envelope from it. Explicit
+/// `envelope_from`/`envelope_to` values take precedence over `Sender`/`From` and
+/// `To`/`Cc`/`Bcc`.
+pub fn parse(
+    message: &[u8],
+    envelope_from: Option<&str>,
+    envelope_to: &[String],
+) -> Result<RawMessage, String> {
+    let message = normalize_line_endings(message);
+    let (header, body) = match find(&message, b"\r\n\r\n") {
+        Some(pos) => message.split_at(pos + 2),
+        None => (&message[..], &b""[..]),
+    };
+
+    let mut fields: Vec<(String, String, &[u8])> = Vec::new();
+    let mut start = 0;
+    for line_end in positions(header, b"\r\n") {
+        let next = line_end + 2;
+        // continuation lines start with whitespace and belong to the previous field
+        if matches!(header.get(next), Some(b' ' | b'\t')) {
+            continue;
+        }
+        let field = &header[start..next];
+        let text = String::from_utf8_lossy(field);
+        let (name, value) = text
+            .split_once(':')
+            .filter(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_ascii_graphic()))
+            .ok_or_else(|| {
+                format!(
+                    "malformed header line: {}",
+                    text.lines().next().unwrap_or("")
+                )
+            })?;
+        fields.push((
+            name.to_string(),
+            value.replace("\r\n", "").trim().to_string(),
+            field,
+        ));
+        start = next;
+    }
+
+    let header_value = |name: &str| {
+        fields
+            .iter()
+            .find(|(field_name, _, _)| field_name.eq_ignore_ascii_case(name))
+            .map(|(_, value, _)| value.as_str())
+    };
+    let Some(from) = header_value("From") else {
+        return Err("message has no From header".into());
+    };
+
+    let envelope_from = match envelope_from {
+        Some(addr) => addr
+            .trim()
+            .parse::<Address>()
+            .map_err(|_| "invalid envelope_from".to_string())?,
+        None => {
+            let sender = header_value("Sender").unwrap_or(from);
+            mailboxes(sender, "Sender/From")?
+                .into_iter()
+                .next()
+                .map(|mailbox| mailbox.email)
+                .ok_or("message has no sender address")?
+        }
+    };
+
+    let envelope_to = if envelope_to.is_empty() {
+        let mut recipients = Vec::new();
+        for (name, value, _) in &fields {
+            if ["To", "Cc", "Bcc"]
+                .iter()
+                .any(|header| header.eq_ignore_ascii_case(name))
+                && !value.is_empty()
+            {
+                recipients.extend(
+                    mailboxes(value, name)?
+                        .into_iter()
+                        .map(|mailbox| mailbox.email),
+                );
+            }
+        }
+        recipients
+    } else {
+        envelope_to
+            .iter()
+            .map(|addr| addr.trim().parse::<Address>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| "invalid envelope_to".to_string())?
+    };
+    let envelope = Envelope::new(Some(envelope_from), envelope_to)
+        .map_err(|_| "message has no recipients".to_string())?;
+
+    // Bcc recipients must not see each other
+    let mut formatted = Vec::with_capacity(message.len());
+    for (name, _, field) in &fields {
+        if !name.eq_ignore_ascii_case("Bcc") {
+            formatted.extend_from_slice(field);
+        }
+    }
+    formatted.extend_from_slice(&header[start..]);
+    let body_start = formatted.len();
+    formatted.extend_from_slice(body);
+
+    Ok(RawMessage {
+        envelope,
+        formatted,
+        message_id: header_value("Message-ID").map(str::to_string),
+        body_start,
+        fields: fields
+            .into_iter()
+            .filter(|(name, _, _)| !name.eq_ignore_ascii_case("Bcc"))
+            .map(|(name, value, _)| (name, value))
+            .collect(),
+    })
+}
+
+impl RawMessage {
+    /// Copy as lettre [`Message`] with the same header fields and body, for DKIM signing.
+    /// lettre keeps one field per name, the later one wins as DKIM signs fields bottom-up.
+    /// With relaxed canonicalization the signature does not depend on how lettre folds
+    /// the fields.
+    pub fn to_message(&self) -> Option<Message> {
+        // the builder requires a From header, all fields are replaced below
+        let body = &self.formatted[self.body_start..];
+        let mut message = Message::builder()
+            .from(self.envelope.from()?.clone().into())
+            .envelope(self.envelope.clone())
+            .body(Body::dangerous_pre_encoded(
+                body.strip_prefix(b"\r\n").unwrap_or(body).to_vec(),
+                ContentTransferEncoding::Binary,
+            ))
+            .ok()?;
+        let headers = message.headers_mut();
+        *headers = Headers::new();
+        for (name, value) in &self.fields {
+            headers.insert_raw(HeaderValue::dangerous_new_pre_encoded(
+                HeaderName::new_from_ascii(name.clone()).ok()?,
+                value.clone(),
+                value.clone(),
+            ));
+        }
+        Some(message)
+    }
+
+    /// Adds a header field on top, e.g. a `DKIM-Signature`.
+    pub fn prepend_header(&mut self, name: &'static str, value: &str) {
+        let mut headers = Headers::new();
+        headers.insert_raw(HeaderValue::new(
+            HeaderName::new_from_ascii_str(name),
+            value.to_string(),
+        ));
+        let field = headers.to_string().into_bytes();
+        self.body_start += field.len();
+        self.formatted.splice(0..0, field);
+        self.fields.insert(0, (name.to_string(), value.to_string()));
+    }
+}
+
+fn mailboxes(value: &str, header: &str) -> Result<Mailboxes, String> {
+    value
+        .parse::<Mailboxes>()
+        .map_err(|_| format!("invalid addresses in {} header", header))
+}
+
+// SMTP requires CRLF, but generated messages often use bare LF
+fn normalize_line_endings(message: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(message.len());
+    let mut previous = 0;
+    for &byte in message {
+        if byte == b'\n' && previous != b'\r' {
+            normalized.push(b'\r');
+        }
+        normalized.push(byte);
+        previous = byte;
+    }
+    normalized
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn positions<'a>(haystack: &'a [u8], needle: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+    haystack
+        .windows(needle.len())
+        .enumerate()
+        .filter(move |(_, window)| *window == needle)
+        .map(|(pos, _)| pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &str = "From: Jane <jane@example.org>\n\
+        To: a@example.org,\n b@example.org\n\
+        Bcc: hidden@example.org\n\
+        Subject: Hi\n\
+        \n\
+        Hello\n";
+
+    #[test]
+    fn derives_envelope_from_headers() {
+        let raw = parse(MESSAGE.as_bytes(), None, &[]).unwrap();
+        assert_eq!(raw.envelope.from().unwrap().to_string(), "jane@example.org");
+        assert_eq!(
+            raw.envelope
+                .to()
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>(),
+            ["a@example.org", "b@example.org", "hidden@example.org"]
+        );
+        assert_eq!(
+            String::from_utf8(raw.formatted).unwrap(),
+            "From: Jane <jane@example.org>\r\nTo: a@example.org,\r\n b@example.org\r\nSubject: Hi\r\n\r\nHello\r\n"
+        );
+    }
+
+    #[test]
+    fn explicit_envelope_takes_precedence() {
+        let raw = parse(
+            MESSAGE.as_bytes(),
+            Some("bounces@example.org"),
+            &["c@example.org".into()],
+        )
+        .unwrap();
+        assert_eq!(
+            raw.envelope.from().unwrap().to_string(),
+            "bounces@example.org"
+        );
+        assert_eq!(raw.envelope.to().len(), 1);
+    }
+
+    #[test]
+    fn rejects_incomplete_messages() {
+        assert!(parse(b"To: a@example.org\r\n\r\nHi", None, &[]).is_err());
+        assert!(parse(b"From: a@example.org\r\n\r\nHi", None, &[]).is_err());
+        assert!(parse(b"From a@example.org\r\n\r\nHi", None, &[]).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::env;
+
+use lettre::Address;
+
+/// Restricts the recipients mails may be sent to. `RECIPIENT_ALLOWLIST` and
+/// `RECIPIENT_DENYLIST` are comma separated patterns: a domain (`example.org`), a domain
+/// wildcard (`*.example.org`) or an address (`jane@example.org`, `*@example.org`). With an
+/// allowlist only matching recipients are accepted, the denylist always wins.
+#[derive(Debug, Clone, Default)]
+pub struct RecipientPolicy {
+    pub allowlist: Vec<String>,
+    pub denylist: Vec<String>,
+}
+
+impl RecipientPolicy {
+    pub fn from_env() -> Self {
+        let list = |name| {
+            env::var(name)
+                .map(|value| Self::parse_list(&value))
+                .unwrap_or_default()
+        };
+        Self {
+            allowlist: list("RECIPIENT_ALLOWLIST"),
+            denylist: list("RECIPIENT_DENYLIST"),
+        }
+    }
+
+    fn parse_list(value: &str) -> Vec<String> {
+        value
+            .split(',')
+            .map(|pattern| pattern.trim().to_lowercase())
+            .filter(|pattern| !pattern.is_empty())
+            .collect()
+    }
+
+    pub fn allows(&self, recipient: &Address) -> bool {
+        let address = recipient.to_string().to_lowercase();
+        let domain = recipient.domain().to_lowercase();
+        let matches = |pattern: &String| match pattern.contains('@') {
+            true => glob(pattern, &address),
+            false => glob(pattern, &domain),
+        };
+        (self.allowlist.is_empty() || self.allowlist.iter().any(matches))
+            && !self.denylist.iter().any(matches)
+    }
+
+    /// Recipients the policy does not allow, in the given order.
+    pub fn rejected<'a>(&self, recipients: impl IntoIterator<Item = &'a Address>) -> Vec<String> {
+        recipients
+            .into_iter()
+            .filter(|recipient| !self.allows(recipient))
+            .map(|recipient| recipient.to_string())
+            .collect()
+    }
+}
+
+// `*` matches any sequence of characters, everything else literally
+fn glob(pattern: &str, value: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == value,
+        Some((prefix, rest)) => {
+            let Some(value) = value.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=value.len())
+                .filter(|&i| value.is_char_boundary(i))
+                .any(|i| glob(rest, &value[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowlist: &str, denylist: &str) -> RecipientPolicy {
+        RecipientPolicy {
+            allowlist: RecipientPolicy::parse_list(allowlist),
+            denylist: RecipientPolicy::parse_list(denylist),
+        }
+    }
+
+    fn address(value: &str) -> Address {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_domains_wildcards_and_addresses() {
+        let policy = policy("example.org, *.example.org, partner@other.org", "");
+        assert!(policy.allows(&address("jane@Example.org")));
+        assert!(policy.allows(&address("jane@staging.example.org")));
+        assert!(policy.allows(&address("partner@other.org")));
+        assert!(!policy.allows(&address("jane@other.org")));
+        assert!(!policy.allows(&address("jane@badexample.org")));
+        assert!(RecipientPolicy::default().allows(&address("jane@other.org")));
+    }
+
+    #[test]
+    fn denylist_wins() {
+        let policy = policy("*.example.org", "ceo@corp.example.org, *@test.example.org");
+        let recipients = [
+            address("jane@corp.example.org"),
+            address("CEO@corp.example.org"),
+            address("bot@test.example.org"),
+        ];
+        assert_eq!(
+            policy.rejected(&recipients),
+            ["CEO@corp.example.org", "bot@test.example.org"]
+        );
+        assert!(RecipientPolicy::parse_list(" , ").is_empty());
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::Path;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use rocket::fs::TempFile;
+use rocket::http::Status;
+use rocket::serde::{json::serde_json, Deserialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::address;
+use crate::calendar;
+use crate::problem::Problem;
+
+/// A send request after validation, the same whether it came as form or as JSON.
+/// Addresses are parsed, `send_at` is a timestamp and attachments are decoded.
+pub struct MailRequest {
+    pub subject: Option<String>,
+    pub from_address: Option<String>,
+    pub from_name: Option<String>,
+    pub to: Vec<Mailbox>,
+    pub cc: Vec<Mailbox>,
+    pub bcc: Vec<Mailbox>,
+    pub content_html: Option<String>,
+    pub content_text: Option<String>,
+    pub content_markdown: Option<String>,
+    pub template: Option<String>,
+    pub variables: serde_json::Value,
+    /// regular and inline attachments, the latter with a `content_id`
+    pub attachments: Vec<AttachmentFile>,
+    pub inline_data_uris: bool,
+    pub reply_to: Option<String>,
+    pub headers: HashMap<String, String>,
+    pub send_at: Option<OffsetDateTime>,
+    pub protection: Protection,
+    pub text_from_html: Option<bool>,
+    pub inline_css: Option<bool>,
+    pub sanitize_html: Option<bool>,
+    pub event: Option<calendar::Event>,
+    pub list_unsubscribe: Option<String>,
+    pub one_click_unsubscribe: bool,
+}
+
+impl MailRequest {
+    pub fn from_form(params: &MailParameterForm<'_>) -> Result<Self, Problem> {
+        // inline files are referenced by their file name, e.g. <img src="cid:logo.png">
+        let inline_attachments = params.inline_attachments.iter().map(|attachment| {
+            let attachment = form_attachment(attachment)?;
+            Ok(AttachmentFile {
+                content_id: Some(attachment.filename.clone()),
+                ..attachment
+            })
+        });
+        Self {
+            subject: params.subject.clone(),
+            from_address: params.from_address.clone(),
+            from_name: params.from_name.clone(),
+            to: parse_recipients("to_address", &params.to_addresses)?,
+            cc: parse_recipients("cc_address", &params.cc_addresses)?,
+            bcc: parse_recipients("bcc_address", &params.bcc_addresses)?,
+            content_html: params.content_html.clone(),
+            content_text: params.content_text.clone(),
+            content_markdown: params.content_markdown.clone(),
+            template: params.template.clone(),
+            variables: serde_json::to_value(&params.variables)
+                .map_err(|e| Problem::invalid("variables", e.to_string()))?,
+            attachments: params
+                .attachments
+                .iter()
+                .map(form_attachment)
+                .chain(inline_attachments)
+                .collect::<Result<_, _>>()?,
+            inline_data_uris: params.inline_data_uris,
+            reply_to: params.reply_to.clone(),
+            headers: params.headers.clone(),
+            send_at: parse_send_at(&params.send_at)?,
+            protection: Protection {
+                smime_sign: params.smime_sign,
+                smime_encrypt: params.smime_encrypt,
+                pgp_sign: params.pgp_sign,
+                pgp_encrypt: params.pgp_encrypt,
+            },
+            text_from_html: params.text_from_html,
+            inline_css: params.inline_css,
+            sanitize_html: params.sanitize_html,
+            event: Some(params.event.clone()).filter(|event| !event.is_empty()),
+            list_unsubscribe: params.list_unsubscribe.clone(),
+            one_click_unsubscribe: params.one_click_unsubscribe,
+        }
+        .validate("to_address")
+    }
+
+    pub fn from_json(params: MailParameterJson) -> Result<Self, Problem> {
+        Self {
+            to: parse_recipients("to_addresses", &params.to_addresses)?,
+            cc: parse_recipients("cc_addresses", &params.cc_addresses)?,
+            bcc: parse_recipients("bcc_addresses", &params.bcc_addresses)?,
+            attachments: params
+                .attachments
+                .iter()
+                .flatten()
+                .map(json_attachment)
+                .collect::<Result<_, _>>()?,
+            send_at: parse_send_at(&params.send_at)?,
+            subject: params.subject,
+            from_address: params.from_address,
+            from_name: params.from_name,
+            content_html: params.content_html,
+            content_text: params.content_text,
+            content_markdown: params.content_markdown,
+            template: params.template,
+            variables: params.variables.unwrap_or_default(),
+            inline_data_uris: params.inline_data_uris.unwrap_or(false),
+            reply_to: params.reply_to,
+            headers: params.headers.unwrap_or_default(),
+            protection: Protection {
+                smime_sign: params.smime_sign.unwrap_or(false),
+                smime_encrypt: params.smime_encrypt.unwrap_or(false),
+                pgp_sign: params.pgp_sign.unwrap_or(false),
+                pgp_encrypt: params.pgp_encrypt.unwrap_or(false),
+            },
+            text_from_html: params.text_from_html,
+            inline_css: params.inline_css,
+            sanitize_html: params.sanitize_html,
+            event: params.event,
+            list_unsubscribe: params.list_unsubscribe,
+            one_click_unsubscribe: params.one_click_unsubscribe.unwrap_or(false),
+        }
+        .validate("to_addresses")
+    }
+
+    // rules that do not depend on how the request was encoded, the subject is checked
+    // after rendering since it may come from a template
+    fn validate(mut self, to_field: &str) -> Result<Self, Problem> {
+        // every recipient gets the mail once, in the first of To, Cc and Bcc naming it
+        let mut seen = HashSet::new();
+        for recipients in [&mut self.to, &mut self.cc, &mut self.bcc] {
+            recipients.retain(|mailbox| seen.insert(mailbox.email.to_string().to_lowercase()));
+        }
+        if self.to.is_empty() {
+            return Err(Problem::invalid(to_field, "missing or empty"));
+        }
+        Ok(self)
+    }
+
+    /// Hash of the request, so a reused Idempotency-Key with other content is detected.
+    /// Form and JSON requests with the same content get the same fingerprint.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Hash for MailRequest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // no `..`, so new fields cannot be forgotten here
+        let Self {
+            subject,
+            from_address,
+            from_name,
+            to,
+            cc,
+            bcc,
+            content_html,
+            content_text,
+            content_markdown,
+            template,
+            variables,
+            attachments,
+            inline_data_uris,
+            reply_to,
+            headers,
+            send_at,
+            protection,
+            text_from_html,
+            inline_css,
+            sanitize_html,
+            event,
+            list_unsubscribe,
+            one_click_unsubscribe,
+        } = self;
+        (subject, from_address, from_name, to, cc, bcc).hash(state);
+        (
+            content_html,
+            content_text,
+            content_markdown,
+            template,
+            variables,
+        )
+            .hash(state);
+        (attachments, inline_data_uris, reply_to, send_at, protection).hash(state);
+        headers.iter().collect::<BTreeMap<_, _>>().hash(state);
+        (text_from_html, inline_css, sanitize_html, event).hash(state);
+        (list_unsubscribe, one_click_unsubscribe).hash(state);
+    }
+}
+
+fn parse_recipients(field: &str, values: &[String]) -> Result<Vec<Mailbox>, Problem> {
+    address::parse_list(values).map_err(|e| Problem::invalid(field, e))
+}
+
+pub fn parse_send_at(send_at: &Option<String>) -> Result<Option<OffsetDateTime>, Problem> {
+    match send_at {
+        Some(send_at) => OffsetDateTime::parse(send_at.trim(), &Rfc3339)
+            .map(Some)
+            .map_err(|e| Problem::invalid("send_at", format!("not an RFC 3339 timestamp: {}", e))),
+        None => Ok(None),
+    }
+}
+
+/// Signing and encryption requested for a mail.
+#[derive(Hash)]
+pub struct Protection {
+    pub smime_sign: bool,
+    pub smime_encrypt: bool,
+    pub pgp_sign: bool,
+    pub pgp_encrypt: bool,
+}
+
+/// File to be attached, independent of how it was uploaded.
+pub struct AttachmentFile {
+    pub filename: String,
+    /// set for inline parts referenced from the HTML as `cid:<content_id>`
+    pub content_id: Option<String>,
+    pub content_type: ContentType,
+    pub body: Vec<u8>,
+}
+
+impl Hash for AttachmentFile {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (&self.filename, &self.content_id, &self.body).hash(state);
+        // ContentType implements no Hash
+        format!("{:?}", self.content_type).hash(state);
+    }
+}
+
+pub fn parse_content_type(content_type: Option<String>) -> ContentType {
+    match content_type {
+        Some(content_type) => content_type
+            .parse()
+            .unwrap_or_else(|_| "application/octet-stream".parse().unwrap()),
+        None => "application/octet-stream".parse().unwrap(),
+    }
+}
+
+fn form_attachment(attachment: &TempFile<'_>) -> Result<AttachmentFile, Problem> {
+    Ok(AttachmentFile {
+        filename: match attachment.name() {
+            Some(safe_name) => {
+                let name_no_ext = Path::new(safe_name);
+                let unsafe_name = attachment
+                    .raw_name()
+                    .unwrap_or("".into())
+                    .dangerous_unsafe_unsanitized_raw()
+                    .as_str();
+                let ext_part = Path::new(unsafe_name).extension();
+                let extension = match ext_part {
+                    Some(ext) => ext.to_os_string(),
+                    None => OsString::from(""),
+                };
+                name_no_ext
+                    .with_extension(extension)
+                    .into_os_string()
+                    .into_string()
+                    .unwrap_or(safe_name.into())
+            }
+            None => "attachment".to_string(),
+        },
+        content_id: None,
+        body: match attachment {
+            TempFile::File { path, .. } => fs::read(path).map_err(|e| {
+                Problem::from((
+                    Status::InternalServerError,
+                    format!("cannot read uploaded attachment: {}", e),
+                ))
+            })?,
+            TempFile::Buffered { content } => content.to_vec(),
+        },
+        content_type: parse_content_type(
+            attachment
+                .content_type()
+                .map(|content_type| content_type.to_string()),
+        ),
+    })
+}
+
+#[derive(FromForm)]
+pub struct MailParameterForm<'r> {
+    subject: Option<String>,
+    #[field(name = "attachment")]
+    attachments: Vec<TempFile<'r>>,
+    #[field(name = "inline_attachment")]
+    inline_attachments: Vec<TempFile<'r>>,
+    from_address: Option<String>,
+    from_name: Option<String>,
+    #[field(validate = len(1..), name = "to_address")]
+    to_addresses: Vec<String>,
+    #[field(name = "cc_address")]
+    cc_addresses: Vec<String>,
+    #[field(name = "bcc_address")]
+    bcc_addresses: Vec<String>,
+    content_html: Option<String>,
+    content_text: Option<String>,
+    content_markdown: Option<String>,
+    template: Option<String>,
+    variables: HashMap<String, String>,
+    inline_data_uris: bool,
+    reply_to: Option<String>,
+    headers: HashMap<String, String>,
+    send_at: Option<String>,
+    smime_sign: bool,
+    smime_encrypt: bool,
+    pgp_sign: bool,
+    pgp_encrypt: bool,
+    text_from_html: Option<bool>,
+    inline_css: Option<bool>,
+    sanitize_html: Option<bool>,
+    event: calendar::Event,
+    list_unsubscribe: Option<String>,
+    one_click_unsubscribe: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct MailParameterJson {
+    subject: Option<String>,
+    from_address: Option<String>,
+    from_name: Option<String>,
+    #[serde(deserialize_with = "address::one_or_many")]
+    to_addresses: Vec<String>,
+    #[serde(default, deserialize_with = "address::one_or_many")]
+    cc_addresses: Vec<String>,
+    #[serde(default, deserialize_with = "address::one_or_many")]
+    bcc_addresses: Vec<String>,
+    content_html: Option<String>,
+    content_text: Option<String>,
+    content_markdown: Option<String>,
+    template: Option<String>,
+    variables: Option<serde_json::Value>,
+    attachments: Option<Vec<AttachmentJson>>,
+    inline_data_uris: Option<bool>,
+    reply_to: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    send_at: Option<String>,
+    smime_sign: Option<bool>,
+    smime_encrypt: Option<bool>,
+    pgp_sign: Option<bool>,
+    pgp_encrypt: Option<bool>,
+    text_from_html: Option<bool>,
+    inline_css: Option<bool>,
+    sanitize_html: Option<bool>,
+    event: Option<calendar::Event>,
+    list_unsubscribe: Option<String>,
+    one_click_unsubscribe: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AttachmentJson {
+    filename: String,
+    content_type: Option<String>,
+    /// base64 encoded file content
+    content: String,
+    /// makes the attachment an inline part, referenced from the HTML as `cid:<content_id>`
+    content_id: Option<String>,
+}
+
+fn json_attachment(attachment: &AttachmentJson) -> Result<AttachmentFile, Problem> {
+    // line breaks are common in base64 output of other tools
+    let content: String = attachment
+        .content
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+    let body = BASE64_STANDARD.decode(content).map_err(|e| {
+        Problem::invalid(
+            "attachments",
+            format!(
+                "attachment \"{}\" content is not base64: {}",
+                attachment.filename, e
+            ),
+        )
+    })?;
+    // only the file name is meaningful to the recipient, drop any directories
+    let filename = Path::new(&attachment.filename)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("attachment")
+        .to_string();
+    let content_id = match &attachment.content_id {
+        Some(content_id) => {
+            let content_id = content_id
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>');
+            if content_id.is_empty()
+                || content_id
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control() || "<>\"".contains(c))
+            {
+                return Err(Problem::invalid(
+                    "attachments",
+                    format!(
+                        "attachment \"{}\" has an invalid content_id",
+                        attachment.filename
+                    ),
+                ));
+            }
+            Some(content_id.to_string())
+        }
+        None => None,
+    };
+    Ok(AttachmentFile {
+        filename,
+        content_id,
+        content_type: parse_content_type(attachment.content_type.clone()),
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(value: serde_json::Value) -> Result<MailRequest, Problem> {
+        MailRequest::from_json(serde_json::from_value(value).unwrap())
+    }
+
+    #[test]
+    fn converts_json_requests() {
+        let request = json(serde_json::json!({
+            "subject": "Hello",
+            "to_addresses": "Jane Doe <jane@example.org>; bob@example.org",
+            "cc_addresses": ["JANE@example.org", "carol@example.org"],
+            "bcc_addresses": ["Audit <audit@example.org>", "Bob <bob@example.org>"],
+            "send_at": "2030-01-01T12:00:00Z",
+            "attachments": [{"filename": "../notes.txt", "content": "aGVs\nbG8="}]
+        }))
+        .unwrap();
+        assert_eq!(request.to.len(), 2);
+        assert_eq!(request.to[0].name.as_deref(), Some("Jane Doe"));
+        assert_eq!(request.cc, ["carol@example.org".parse().unwrap()]);
+        assert_eq!(request.bcc.len(), 1);
+        assert_eq!(request.bcc[0].name.as_deref(), Some("Audit"));
+        assert!(request.send_at.is_some());
+        assert_eq!(request.attachments[0].filename, "notes.txt");
+        assert_eq!(request.attachments[0].body, b"hello");
+        assert!(!request.protection.smime_sign);
+    }
+
+    #[test]
+    fn fingerprints_normalized_requests() {
+        let fingerprint = |value| json(value).unwrap().fingerprint();
+        let request = fingerprint(serde_json::json!({
+            "to_addresses": "jane@example.org",
+            "headers": {"X-A": "1", "X-B": "2"},
+            "attachments": [{"filename": "a.txt", "content": "aGVsbG8="}]
+        }));
+        assert_eq!(
+            request,
+            fingerprint(serde_json::json!({
+                "attachments": [{"filename": "dir/a.txt", "content": "aGVs\nbG8="}],
+                "headers": {"X-B": "2", "X-A": "1"},
+                "to_addresses": ["jane@example.org", "JANE@example.org"],
+                "smime_sign": false
+            }))
+        );
+        assert_ne!(
+            request,
+            fingerprint(serde_json::json!({
+                "to_addresses": "jane@example.org",
+                "headers": {"X-A": "1", "X-B": "2"},
+                "attachments": [{"filename": "a.txt", "content": "aGVsbG8h"}]
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_recipients() {
+        let field = |value| json(value).err().unwrap().errors[0].field.clone();
+        assert_eq!(
+            field(serde_json::json!({"to_addresses": []})),
+            "to_addresses"
+        );
+        assert_eq!(
+            field(serde_json::json!({"to_addresses": [","]})),
+            "to_addresses"
+        );
+        assert_eq!(
+            field(serde_json::json!({"to_addresses": ["a@b.c"], "cc_addresses": ["ab"]})),
+            "cc_addresses"
+        );
+        assert_eq!(
+            field(serde_json::json!({"to_addresses": ["a@b.c"], "send_at": "soon"})),
+            "send_at"
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+
+use crate::idempotency::Response;
+use crate::problem::Problem;
+use crate::queue::{MessageRecord, Queue};
+
+/// Response format negotiated via `Accept`. Plain text unless JSON is preferred.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Format {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(match req.accept() {
+            Some(accept) if accept.preferred().media_type().is_json() => Format::Json,
+            _ => Format::Text,
+        })
+    }
+}
+
+/// Answer of the send endpoints: the message ID as text, the message record as JSON or
+/// a problem for rejected requests.
+#[derive(Responder)]
+pub enum SendResponse {
+    Text((Status, String)),
+    Json((Status, Json<MessageRecord>)),
+    Problem(Problem),
+}
+
+/// Turns the response of a send endpoint into the requested format. Accepted mails are
+/// answered with their queue record, which gains the SMTP reply of every delivery
+/// attempt later on (see `GET /messages/{id}`).
+pub fn send_response(format: Format, queue: &Queue, response: Response) -> SendResponse {
+    let (status, body) = match response {
+        Ok(response) => response,
+        Err(problem) => return SendResponse::Problem(problem),
+    };
+    if format == Format::Json && status == Status::Accepted {
+        if let Some(record) = uuid::Uuid::parse_str(&body)
+            .ok()
+            .and_then(|id| queue.status(&id))
+        {
+            return SendResponse::Json((status, Json(record)));
+        }
+    }
+    SendResponse::Text((status, body))
+}
@@ -0,0 +1,319 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use lettre::message::header::{ContentDisposition, ContentType};
+use lettre::message::{Body, MultiPart, SinglePart};
+use lettre::Address;
+use openssl::asn1::Asn1Time;
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::symm::Cipher;
+use openssl::x509::X509;
+
+#[derive(Debug, PartialEq)]
+pub enum SmimeError {
+    /// signing was requested, but no certificate is configured
+    SigningNotConfigured,
+    /// encryption was requested, but no recipient certificate directory is configured
+    EncryptionNotConfigured,
+    /// no usable certificate for the recipient address in the certificate directory
+    MissingCertificate(String),
+    Crypto(String),
+}
+
+impl fmt::Display for SmimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmimeError::SigningNotConfigured => write!(f, "S/MIME signing is not configured"),
+            SmimeError::EncryptionNotConfigured => {
+                write!(f, "S/MIME encryption is not configured")
+            }
+            SmimeError::MissingCertificate(addr) => {
+                write!(f, "no valid S/MIME certificate for recipient {}", addr)
+            }
+            SmimeError::Crypto(e) => write!(f, "S/MIME error: {}", e),
+        }
+    }
+}
+
+impl From<openssl::error::ErrorStack> for SmimeError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        SmimeError::Crypto(e.to_string())
+    }
+}
+
+/// Body returned by [`Smime::protect`]: `multipart/signed` or `application/pkcs7-mime`.
+pub enum Protected {
+    Multipart(MultiPart),
+    Singlepart(SinglePart),
+}
+
+struct Signer {
+    cert: X509,
+    key: PKey<Private>,
+    chain: Stack<X509>,
+}
+
+/// S/MIME settings: signing certificate and key from `SMIME_CERT_FILE`/`SMIME_KEY_FILE`,
+/// recipient certificates as `<address>.pem` files in `SMIME_CERT_DIR`.
+pub struct Smime {
+    signer: Option<Signer>,
+    pub cert_dir: Option<PathBuf>,
+}
+
+impl Smime {
+    pub fn from_env() -> Self {
+        let signer = env::var("SMIME_CERT_FILE").ok().map(|cert_file| {
+            let key_file = env::var("SMIME_KEY_FILE").expect("SMIME_KEY_FILE is not set");
+            let certs = fs::read(&cert_file)
+                .ok()
+                .and_then(|pem| X509::stack_from_pem(&pem).ok())
+                .unwrap_or_else(|| panic!("cannot read S/MIME certificate {}", cert_file));
+            let key = fs::read(&key_file)
+                .ok()
+                .and_then(|pem| PKey::private_key_from_pem(&pem).ok())
+                .unwrap_or_else(|| panic!("cannot read S/MIME key {}", key_file));
+            Signer::new(certs, key)
+                .unwrap_or_else(|| panic!("S/MIME certificate file {} is empty", cert_file))
+        });
+        Self {
+            signer,
+            cert_dir: env::var("SMIME_CERT_DIR").ok().map(PathBuf::from),
+        }
+    }
+
+    pub fn can_sign(&self) -> bool {
+        self.signer.is_some()
+    }
+
+    /// Signs and/or encrypts `body`. With `encrypt_to` the result can only be read by
+    /// these recipients, so all of them need a certificate.
+    pub fn protect(
+        &self,
+        body: MultiPart,
+        sign: bool,
+        encrypt_to: Option<&[Address]>,
+    ) -> Result<Protected, SmimeError> {
+        let recipients = match encrypt_to {
+            Some(addrs) => Some(self.recipient_certs(addrs)?),
+            None => None,
+        };
+
+        let content = if sign {
+            let signer = self
+                .signer
+                .as_ref()
+                .ok_or(SmimeError::SigningNotConfigured)?;
+            signer.sign(body)?
+        } else {
+            body
+        };
+        let Some(recipients) = recipients else {
+            return Ok(Protected::Multipart(content));
+        };
+
+        let p7 = Pkcs7::encrypt(
+            &recipients,
+            &content.formatted(),
+            Cipher::aes_256_cbc(),
+            Pkcs7Flags::BINARY,
+        )?;
+        Ok(Protected::Singlepart(
+            SinglePart::builder()
+                .header(
+                    ContentType::parse(
+                        "application/pkcs7-mime; smime-type=enveloped-data; name=smime.p7m",
+                    )
+                    .unwrap(),
+                )
+                .header(ContentDisposition::attachment("smime.p7m"))
+                .body(Body::new(p7.to_der()?)),
+        ))
+    }
+
+    fn recipient_certs(&self, addrs: &[Address]) -> Result<Stack<X509>, SmimeError> {
+        let dir = self
+            .cert_dir
+            .as_ref()
+            .ok_or(SmimeError::EncryptionNotConfigured)?;
+        let now = Asn1Time::days_from_now(0)?;
+        let mut certs = Stack::new()?;
+        for addr in addrs {
+            let addr = addr.to_string().to_lowercase();
+            // quoted local parts may contain path separators, e.g. "../../x"@example.org
+            let cert = (!addr.contains(['/', '\\', '\0']) && !addr.contains(".."))
+                .then(|| fs::read(dir.join(format!("{}.pem", addr))).ok())
+                .flatten()
+                .and_then(|pem| X509::from_pem(&pem).ok())
+                .filter(|cert| cert.not_after() > now)
+                .ok_or(SmimeError::MissingCertificate(addr))?;
+            certs.push(cert)?;
+        }
+        Ok(certs)
+    }
+}
+
+impl Signer {
+    fn new(mut certs: Vec<X509>, key: PKey<Private>) -> Option<Self> {
+        if certs.is_empty() {
+            return None;
+        }
+        let cert = certs.remove(0);
+        let mut chain = Stack::new().ok()?;
+        for cert in certs {
+            chain.push(cert).ok()?;
+        }
+        Some(Signer { cert, key, chain })
+    }
+
+    // detached signature, so clients without S/MIME support still show the content
+    fn sign(&self, body: MultiPart) -> Result<MultiPart, SmimeError> {
+        // the line break before the next boundary belongs to the boundary, not the signed part
+        let content = body.formatted();
+        let content = content.strip_suffix(b"\r\n").unwrap_or(&content);
+        let p7 = Pkcs7::sign(
+            &self.cert,
+            &self.key,
+            &self.chain,
+            content,
+            Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+        )?;
+        Ok(
+            MultiPart::signed("application/pkcs7-signature".into(), "sha-256".into())
+                .multipart(body)
+                .singlepart(
+                    SinglePart::builder()
+                        .header(
+                            ContentType::parse("application/pkcs7-signature; name=smime.p7s")
+                                .unwrap(),
+                        )
+                        .header(ContentDisposition::attachment("smime.p7s"))
+                        .body(Body::new(p7.to_der()?)),
+                ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+
+    fn self_signed(addr: &str) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", addr).unwrap();
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    fn test_body() -> MultiPart {
+        MultiPart::alternative_plain_html("Hello".to_string(), "<p>Hello</p>".to_string())
+    }
+
+    #[test]
+    fn signs_with_detached_signature() {
+        let (cert, key) = self_signed("sender@example.org");
+        let smime = Smime {
+            signer: Signer::new(vec![cert.clone()], key),
+            cert_dir: None,
+        };
+        let Ok(Protected::Multipart(signed)) = smime.protect(test_body(), true, None) else {
+            panic!("not signed");
+        };
+
+        let formatted = signed.formatted();
+        let (p7, content) = Pkcs7::from_smime(&formatted).unwrap();
+        let mut certs = Stack::new().unwrap();
+        certs.push(cert).unwrap();
+        let store = openssl::x509::store::X509StoreBuilder::new()
+            .unwrap()
+            .build();
+        p7.verify(
+            &certs,
+            &store,
+            content.as_deref(),
+            None,
+            Pkcs7Flags::NOVERIFY | Pkcs7Flags::BINARY,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn encrypts_to_recipients_with_certificates() {
+        let dir = env::temp_dir().join(format!("rest2smtp-smime-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = self_signed("rcpt@example.org");
+        fs::write(dir.join("rcpt@example.org.pem"), cert.to_pem().unwrap()).unwrap();
+        let smime = Smime {
+            signer: None,
+            cert_dir: Some(dir.clone()),
+        };
+
+        let rcpt: Address = "Rcpt@example.org".parse().unwrap();
+        let Ok(Protected::Singlepart(part)) = smime.protect(test_body(), false, Some(&[rcpt]))
+        else {
+            panic!("not encrypted");
+        };
+        let p7 = Pkcs7::from_der(&base64_body(&part.formatted())).unwrap();
+        let decrypted = p7.decrypt(&key, &cert, Pkcs7Flags::empty()).unwrap();
+        let decrypted = String::from_utf8(decrypted).unwrap();
+        assert!(decrypted.starts_with("Content-Type: multipart/alternative;"));
+        assert!(decrypted.contains("<p>Hello</p>"));
+
+        let other: Address = "other@example.org".parse().unwrap();
+        assert_eq!(
+            smime.protect(test_body(), false, Some(&[other])).err(),
+            Some(SmimeError::MissingCertificate("other@example.org".into()))
+        );
+        assert_eq!(
+            smime.protect(test_body(), true, None).err(),
+            Some(SmimeError::SigningNotConfigured)
+        );
+
+        // a certificate outside of the directory must not be found
+        let inner = dir.join("certs");
+        fs::create_dir_all(inner.join("\"")).unwrap();
+        fs::write(dir.join("x\"@example.org.pem"), cert.to_pem().unwrap()).unwrap();
+        let smime = Smime {
+            signer: None,
+            cert_dir: Some(inner),
+        };
+        let escape: Address = r#""/../../x"@example.org"#.parse().unwrap();
+        assert!(matches!(
+            smime.protect(test_body(), false, Some(&[escape])),
+            Err(SmimeError::MissingCertificate(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn base64_body(part: &[u8]) -> Vec<u8> {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+        let part = String::from_utf8_lossy(part);
+        let (_, body) = part.split_once("\r\n\r\n").unwrap();
+        let body: String = body.chars().filter(|c| !c.is_whitespace()).collect();
+        BASE64_STANDARD.decode(body).unwrap()
+    }
+}
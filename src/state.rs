@@ -0,0 +1,88 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{Ignite, Phase, Rocket, Sentinel};
+
+use crate::headers::HeaderPolicy;
+use crate::html::HtmlProcessing;
+use crate::idempotency::Idempotency;
+use crate::mailer::Mailer;
+use crate::openpgp::OpenPgp;
+use crate::plaintext::PlainText;
+use crate::queue::Queue;
+use crate::recipients::RecipientPolicy;
+use crate::smime::Smime;
+use crate::templates::Templates;
+use crate::unsubscribe::Unsubscribe;
+
+/// The managed state building and queueing a mail needs, as one request guard so the
+/// send routes share it instead of listing every `State` on their own.
+pub struct MailState<'r> {
+    pub mailer: &'r Mailer,
+    pub queue: &'r Queue,
+    pub templates: &'r Templates,
+    pub header_policy: &'r HeaderPolicy,
+    pub smime: &'r Smime,
+    pub openpgp: &'r OpenPgp,
+    pub plaintext: &'r PlainText,
+    pub html_processing: &'r HtmlProcessing,
+    pub unsubscribe: &'r Unsubscribe,
+    pub recipient_policy: &'r RecipientPolicy,
+    pub idempotency: &'r Idempotency,
+}
+
+impl<'r> MailState<'r> {
+    fn from_rocket<P: Phase>(rocket: &'r Rocket<P>) -> Option<Self> {
+        Some(Self {
+            mailer: rocket.state()?,
+            queue: rocket.state()?,
+            templates: rocket.state()?,
+            header_policy: rocket.state()?,
+            smime: rocket.state()?,
+            openpgp: rocket.state()?,
+            plaintext: rocket.state()?,
+            html_processing: rocket.state()?,
+            unsubscribe: rocket.state()?,
+            recipient_policy: rocket.state()?,
+            idempotency: rocket.state()?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MailState<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match Self::from_rocket(req.rocket()) {
+            Some(state) => Outcome::Success(state),
+            None => Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
+
+// like `&State<T>`, Rocket refuses to launch when one of them is not managed
+impl Sentinel for MailState<'_> {
+    fn abort(rocket: &Rocket<Ignite>) -> bool {
+        MailState::from_rocket(rocket).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::get("/")]
+    fn route(_state: MailState<'_>) {}
+
+    #[rocket::async_test]
+    async fn aborts_launch_without_the_managed_state() {
+        let rocket = rocket::build()
+            .manage(RecipientPolicy::default())
+            .mount("/", rocket::routes![route]);
+        let error = rocket.ignite().await.unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            rocket::error::ErrorKind::SentinelAborts(_)
+        ));
+    }
+}
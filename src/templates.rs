@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use rocket::serde::{json::Value, Deserialize, Serialize};
+use time::OffsetDateTime;
+
+const SUBJECT_FILE: &str = "subject.txt";
+const HTML_FILE: &str = "content.html";
+const TEXT_FILE: &str = "content.txt";
+const REVISIONS_DIR: &str = ".revisions";
+
+#[derive(Debug, PartialEq)]
+pub enum TemplateError {
+    UnknownTemplate(String),
+    UnknownRevision(u32),
+    InvalidName(String),
+    Syntax(String),
+    MissingVariable(String),
+    InvalidVariable(String),
+    Storage(String),
+}
+
+impl From<io::Error> for TemplateError {
+    fn from(e: io::Error) -> Self {
+        TemplateError::Storage(e.to_string())
+    }
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::UnknownTemplate(name) => write!(f, "unknown template \"{}\"", name),
+            TemplateError::UnknownRevision(revision) => {
+                write!(f, "unknown template revision {}", revision)
+            }
+            TemplateError::InvalidName(name) => write!(
+                f,
+                "invalid template name \"{}\", allowed are letters, digits, \"_\", \"-\" and \".\"",
+                name
+            ),
+            TemplateError::Syntax(msg) => write!(f, "template syntax error: {}", msg),
+            TemplateError::MissingVariable(name) => {
+                write!(f, "template variable \"{}\" missing", name)
+            }
+            TemplateError::InvalidVariable(name) => write!(
+                f,
+                "template variable \"{}\" must be a string, number or boolean",
+                name
+            ),
+            TemplateError::Storage(msg) => write!(f, "template storage error: {}", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Variable(String),
+}
+
+/// A parsed template text. Placeholders are written as `{{ name }}`, nested
+/// variables are addressed with dots, e.g. `{{ user.name }}`.
+#[derive(Debug, Clone)]
+struct Compiled(Vec<Segment>);
+
+impl Compiled {
+    fn parse(source: &str) -> Result<Compiled, TemplateError> {
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let after_open = &rest[start + 2..];
+            let end = after_open.find("}}").ok_or_else(|| {
+                TemplateError::Syntax(format!("unclosed placeholder at \"{}\"", &rest[start..]))
+            })?;
+            let name = after_open[..end].trim();
+            let valid_name = !name.is_empty()
+                && name.split('.').all(|part| {
+                    !part.is_empty()
+                        && part
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                });
+            if !valid_name {
+                return Err(TemplateError::Syntax(format!(
+                    "invalid variable name \"{}\"",
+                    name
+                )));
+            }
+            segments.push(Segment::Variable(name.to_string()));
+            rest = &after_open[end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        Ok(Compiled(segments))
+    }
+
+    fn render(&self, variables: &Value, html_escape: bool) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Variable(name) => {
+                    let value = name
+                        .split('.')
+                        .try_fold(variables, |value, key| value.get(key))
+                        .ok_or_else(|| TemplateError::MissingVariable(name.clone()))?;
+                    let value = match value {
+                        Value::String(s) => s.clone(),
+                        Value::Number(n) => n.to_string(),
+                        Value::Bool(b) => b.to_string(),
+                        _ => return Err(TemplateError::InvalidVariable(name.clone())),
+                    };
+                    if html_escape {
+                        out.push_str(&escape_html(&value));
+                    } else {
+                        out.push_str(&value);
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Editable source of a template, as stored on disk and exchanged via the template API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TemplateSource {
+    pub subject: String,
+    pub content_html: Option<String>,
+    pub content_text: Option<String>,
+}
+
+impl TemplateSource {
+    /// Reads the template files of `dir`, `None` if there is no `subject.txt`.
+    fn read(dir: &Path) -> io::Result<Option<TemplateSource>> {
+        let read_optional = |file: &str| match fs::read_to_string(dir.join(file)) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        Ok(match read_optional(SUBJECT_FILE)? {
+            Some(subject) => Some(TemplateSource {
+                subject,
+                content_html: read_optional(HTML_FILE)?,
+                content_text: read_optional(TEXT_FILE)?,
+            }),
+            None => None,
+        })
+    }
+
+    fn write(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        for (file, content) in [
+            (SUBJECT_FILE, Some(&self.subject)),
+            (HTML_FILE, self.content_html.as_ref()),
+            (TEXT_FILE, self.content_text.as_ref()),
+        ] {
+            let path = dir.join(file);
+            match content {
+                Some(content) => fs::write(path, content)?,
+                None => match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Named mail template with a subject and at least one of an HTML or text part.
+#[derive(Debug, Clone)]
+pub struct Template {
+    source: TemplateSource,
+    subject: Compiled,
+    html: Option<Compiled>,
+    text: Option<Compiled>,
+}
+
+impl Template {
+    pub fn compile(source: TemplateSource) -> Result<Template, TemplateError> {
+        if source.content_html.is_none() && source.content_text.is_none() {
+            return Err(TemplateError::Syntax(
+                "either content_html or content_text is required".into(),
+            ));
+        }
+        Ok(Template {
+            subject: Compiled::parse(source.subject.trim())?,
+            html: source
+                .content_html
+                .as_deref()
+                .map(Compiled::parse)
+                .transpose()?,
+            text: source
+                .content_text
+                .as_deref()
+                .map(Compiled::parse)
+                .transpose()?,
+            source,
+        })
+    }
+
+    pub fn render(&self, variables: &Value) -> Result<Rendered, TemplateError> {
+        Ok(Rendered {
+            subject: self.subject.render(variables, false)?,
+            html: match &self.html {
+                Some(html) => Some(html.render(variables, true)?),
+                None => None,
+            },
+            text: match &self.text {
+                Some(text) => Some(text.render(variables, false)?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Mail content produced by rendering a template.
+#[derive(Debug, PartialEq)]
+pub struct Rendered {
+    pub subject: String,
+    pub html: Option<String>,
+    pub text: Option<String>,
+}
+
+/// A replaced version of a template, kept for rollbacks.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RevisionInfo {
+    pub revision: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub archived_at: OffsetDateTime,
+}
+
+/// Templates stored in `TEMPLATE_DIR`, one sub directory per template containing
+/// `subject.txt` and `content.html` and/or `content.txt`. Versions replaced via the
+/// template API are archived in the template's `.revisions/<revision>` directory.
+pub struct Templates {
+    pub dir: PathBuf,
+    templates: RwLock<HashMap<String, Template>>,
+}
+
+impl Templates {
+    pub fn from_env() -> Templates {
+        let dir = env::var("TEMPLATE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("templates"));
+        Templates::load(dir)
+    }
+
+    fn load(dir: PathBuf) -> Templates {
+        let mut templates = HashMap::new();
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                    continue;
+                };
+                if !path.is_dir() || !valid_name(name) {
+                    continue;
+                }
+                let template = match TemplateSource::read(&path) {
+                    Ok(Some(source)) => Template::compile(source).map_err(|e| e.to_string()),
+                    Ok(None) => Err(format!("{} missing", SUBJECT_FILE)),
+                    Err(e) => Err(e.to_string()),
+                };
+                match template {
+                    Ok(template) => {
+                        templates.insert(name.to_string(), template);
+                    }
+                    Err(e) => eprintln!("Skipping template \"{}\": {}", name, e),
+                }
+            }
+        }
+        Templates {
+            dir,
+            templates: RwLock::new(templates),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.templates.read().unwrap().len()
+    }
+
+    pub fn render(&self, name: &str, variables: &Value) -> Result<Rendered, TemplateError> {
+        self.templates
+            .read()
+            .unwrap()
+            .get(name)
+            .ok_or_else(|| TemplateError::UnknownTemplate(name.to_string()))?
+            .render(variables)
+    }
+
+    pub fn get(&self, name: &str) -> Result<TemplateSource, TemplateError> {
+        self.templates
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|template| template.source.clone())
+            .ok_or_else(|| TemplateError::UnknownTemplate(name.to_string()))
+    }
+
+    /// Compiles and stores the template, archiving the replaced version.
+    /// Returns `true` if the template did not exist before.
+    pub fn save(&self, name: &str, source: TemplateSource) -> Result<bool, TemplateError> {
+        if !valid_name(name) {
+            return Err(TemplateError::InvalidName(name.to_string()));
+        }
+        let template = Template::compile(source)?;
+
+        let mut templates = self.templates.write().unwrap();
+        let dir = self.dir.join(name);
+        let created = match templates.get(name) {
+            Some(current) => {
+                let revision = revisions(&dir)?.last().map_or(1, |r| r.revision + 1);
+                current
+                    .source
+                    .write(&dir.join(REVISIONS_DIR).join(revision.to_string()))?;
+                false
+            }
+            None => true,
+        };
+        template.source.write(&dir)?;
+        templates.insert(name.to_string(), template);
+        Ok(created)
+    }
+
+    /// Removes the template including all its revisions.
+    pub fn delete(&self, name: &str) -> Result<(), TemplateError> {
+        let mut templates = self.templates.write().unwrap();
+        if !templates.contains_key(name) {
+            return Err(TemplateError::UnknownTemplate(name.to_string()));
+        }
+        fs::remove_dir_all(self.dir.join(name))?;
+        templates.remove(name);
+        Ok(())
+    }
+
+    pub fn revisions(&self, name: &str) -> Result<Vec<RevisionInfo>, TemplateError> {
+        self.get(name)?;
+        Ok(revisions(&self.dir.join(name))?)
+    }
+
+    pub fn revision(&self, name: &str, revision: u32) -> Result<TemplateSource, TemplateError> {
+        self.get(name)?;
+        let dir = self
+            .dir
+            .join(name)
+            .join(REVISIONS_DIR)
+            .join(revision.to_string());
+        TemplateSource::read(&dir)?.ok_or(TemplateError::UnknownRevision(revision))
+    }
+
+    /// Rolls back to an older revision by saving it as the current version.
+    pub fn restore(&self, name: &str, revision: u32) -> Result<(), TemplateError> {
+        let source = self.revision(name, revision)?;
+        self.save(name, source).map(|_| ())
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Archived revisions of the template in `dir`, oldest first.
+fn revisions(dir: &Path) -> io::Result<Vec<RevisionInfo>> {
+    let entries = match fs::read_dir(dir.join(REVISIONS_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut revisions = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Some(revision) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            revisions.push(RevisionInfo {
+                revision,
+                archived_at: entry.metadata()?.modified()?.into(),
+            });
+        }
+    }
+    revisions.sort_by_key(|r| r.revision);
+    Ok(revisions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::serde::json::json;
+
+    fn source(subject: &str, html: Option<&str>, text: Option<&str>) -> TemplateSource {
+        TemplateSource {
+            subject: subject.into(),
+            content_html: html.map(Into::into),
+            content_text: text.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn renders_variables_and_escapes_html() {
+        let template = Template::compile(source(
+            "Hello {{ name }}",
+            Some("<p>{{name}} owes {{ invoice.total }}</p>"),
+            Some("{{ name }}: {{ invoice.paid }}"),
+        ))
+        .unwrap();
+        let rendered = template
+            .render(&json!({"name": "Tom & Jerry", "invoice": {"total": 12.5, "paid": false}}))
+            .unwrap();
+        assert_eq!(
+            rendered,
+            Rendered {
+                subject: "Hello Tom & Jerry".into(),
+                html: Some("<p>Tom &amp; Jerry owes 12.5</p>".into()),
+                text: Some("Tom & Jerry: false".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn reports_missing_and_invalid_variables() {
+        let template = Template::compile(source("{{ a.b }}", None, Some("text"))).unwrap();
+        assert_eq!(
+            template.render(&json!({})).unwrap_err(),
+            TemplateError::MissingVariable("a.b".into())
+        );
+        assert_eq!(
+            template.render(&json!({"a": {"b": [1]}})).unwrap_err(),
+            TemplateError::InvalidVariable("a.b".into())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        assert!(Template::compile(source("Hi {{ name", None, Some(""))).is_err());
+        assert!(Template::compile(source("Hi {{ }}", None, Some(""))).is_err());
+        assert!(Template::compile(source("Hi {{ a b }}", None, Some(""))).is_err());
+        assert!(Template::compile(source("Hi", None, None)).is_err());
+    }
+
+    #[test]
+    fn saved_templates_keep_revisions_for_rollback() {
+        let dir = env::temp_dir().join(format!("rest2smtp-templates-{}", uuid::Uuid::new_v4()));
+        let templates = Templates::load(dir.clone());
+        let v1 = source("v1", Some("<p>one</p>"), None);
+        let v2 = source("v2", None, Some("two"));
+
+        assert_eq!(templates.save("notice", v1.clone()), Ok(true));
+        assert_eq!(templates.save("notice", v2.clone()), Ok(false));
+        assert_eq!(
+            templates.save("../escape", v1.clone()),
+            Err(TemplateError::InvalidName("../escape".into()))
+        );
+        assert!(templates
+            .save("broken", source("{{", None, Some("")))
+            .is_err());
+
+        let reloaded = Templates::load(dir.clone());
+        assert_eq!(reloaded.get("notice"), Ok(v2.clone()));
+        let revisions = reloaded.revisions("notice").unwrap();
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(reloaded.revision("notice", 1), Ok(v1.clone()));
+
+        reloaded.restore("notice", 1).unwrap();
+        assert_eq!(reloaded.get("notice"), Ok(v1));
+        assert_eq!(reloaded.revision("notice", 2), Ok(v2));
+
+        reloaded.delete("notice").unwrap();
+        assert!(reloaded.get("notice").is_err());
+        assert!(!dir.join("notice").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::Address;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+
+/// `List-Unsubscribe` handling. `LIST_UNSUBSCRIBE` is the default target (URL or mailto)
+/// for requests without one. With `UNSUBSCRIBE_URL` and `UNSUBSCRIBE_SECRET` rest2smtp
+/// serves signed one-click links itself and records the opt-outs in `UNSUBSCRIBE_FILE`.
+pub struct Unsubscribe {
+    pub default_target: Option<String>,
+    /// public base URL of rest2smtp, the links point to `<base_url>/unsubscribe/<token>`
+    pub base_url: Option<String>,
+    key: Option<PKey<Private>>,
+    pub file: PathBuf,
+    /// lowercased addresses that unsubscribed
+    opt_outs: Mutex<HashSet<String>>,
+}
+
+impl Unsubscribe {
+    pub fn from_env() -> Self {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let default_target = var("LIST_UNSUBSCRIBE");
+        if let Some(target) = &default_target {
+            check_target(target).unwrap_or_else(|e| panic!("LIST_UNSUBSCRIBE: {}", e));
+        }
+        let base_url = var("UNSUBSCRIBE_URL").map(|url| url.trim_end_matches('/').to_string());
+        let secret = var("UNSUBSCRIBE_SECRET");
+        if base_url.is_some() != secret.is_some() {
+            panic!("UNSUBSCRIBE_URL and UNSUBSCRIBE_SECRET must be set together");
+        }
+        if let Some(url) = &base_url {
+            if !url.starts_with("https://") {
+                panic!("UNSUBSCRIBE_URL must be an https URL");
+            }
+        }
+        let file = var("UNSUBSCRIBE_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("data/unsubscribed.txt"));
+        let unsubscribe = Self::open(file.clone())
+            .unwrap_or_else(|e| panic!("cannot read {}: {}", file.display(), e));
+        let unsubscribe = match base_url.zip(secret) {
+            Some((base_url, secret)) => unsubscribe.with_one_click(base_url, &secret),
+            None => unsubscribe,
+        };
+        Self {
+            default_target,
+            ..unsubscribe
+        }
+    }
+
+    /// The opt-outs recorded in `file`, without a default target or one-click links.
+    pub fn open(file: PathBuf) -> io::Result<Self> {
+        let opt_outs = match fs::read_to_string(&file) {
+            Ok(content) => content
+                .lines()
+                .map(|line| line.trim().to_ascii_lowercase())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            default_target: None,
+            base_url: None,
+            key: None,
+            file,
+            opt_outs: Mutex::new(opt_outs),
+        })
+    }
+
+    /// Serves one-click links below `base_url`, signed with `secret`.
+    pub fn with_one_click(self, base_url: String, secret: &str) -> Self {
+        Self {
+            base_url: Some(base_url),
+            key: Some(PKey::hmac(secret.as_bytes()).expect("invalid HMAC key")),
+            ..self
+        }
+    }
+
+    pub fn opt_outs(&self) -> usize {
+        self.opt_outs.lock().unwrap().len()
+    }
+
+    /// `List-Unsubscribe` (and `List-Unsubscribe-Post` for the one-click link) for a mail to
+    /// `recipients`. `target` falls back to `LIST_UNSUBSCRIBE`; `one_click` adds a signed
+    /// link to rest2smtp, which needs exactly one recipient. Empty if neither applies.
+    /// Errors name the offending request field.
+    pub fn headers(
+        &self,
+        target: Option<&str>,
+        one_click: bool,
+        recipients: &[Address],
+    ) -> Result<Vec<HeaderValue>, (&'static str, String)> {
+        let mut targets = Vec::new();
+        if one_click {
+            let (Some(base_url), Some(key)) = (&self.base_url, &self.key) else {
+                return Err((
+                    "one_click_unsubscribe",
+                    "one-click unsubscribe is not configured".into(),
+                ));
+            };
+            let [recipient] = recipients else {
+                return Err((
+                    "one_click_unsubscribe",
+                    "one_click_unsubscribe requires exactly one recipient".into(),
+                ));
+            };
+            targets.push(format!(
+                "{}/unsubscribe/{}",
+                base_url,
+                token(key, recipient.as_ref())
+            ));
+        }
+        match target.map(str::trim).or(self.default_target.as_deref()) {
+            Some(target) => {
+                check_target(target).map_err(|e| ("list_unsubscribe", e))?;
+                targets.push(target.to_string());
+            }
+            None if targets.is_empty() => return Ok(Vec::new()),
+            None => {}
+        }
+
+        let mut headers = vec![HeaderValue::new(
+            HeaderName::new_from_ascii_str("List-Unsubscribe"),
+            targets
+                .iter()
+                .map(|target| format!("<{}>", target))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )];
+        // RFC 8058: only our own link is known to accept the one-click POST
+        if one_click {
+            headers.push(HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+                "List-Unsubscribe=One-Click".into(),
+            ));
+        }
+        Ok(headers)
+    }
+
+    /// Recipients that unsubscribed, mails to them are refused.
+    pub fn unsubscribed(&self, recipients: &[Address]) -> Vec<String> {
+        let opt_outs = self.opt_outs.lock().unwrap();
+        recipients
+            .iter()
+            .map(|recipient| recipient.to_string().to_ascii_lowercase())
+            .filter(|recipient| opt_outs.contains(recipient))
+            .collect()
+    }
+
+    /// Address of a valid one-click token.
+    pub fn verify(&self, token_value: &str) -> Option<String> {
+        let key = self.key.as_ref()?;
+        let (encoded, _) = token_value.split_once('.')?;
+        let address = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(encoded).ok()?).ok()?;
+        let expected = token(key, &address);
+        (expected.len() == token_value.len()
+            && memcmp::eq(expected.as_bytes(), token_value.as_bytes()))
+        .then_some(address)
+    }
+
+    /// Records the opt-out of `address`, appending it to `UNSUBSCRIBE_FILE` once.
+    pub fn record(&self, address: &str) -> io::Result<()> {
+        let address = address.to_ascii_lowercase();
+        let mut opt_outs = self.opt_outs.lock().unwrap();
+        if opt_outs.contains(&address) {
+            return Ok(());
+        }
+        if let Some(dir) = self.file.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?;
+        writeln!(file, "{}", address)?;
+        file.sync_data()?;
+        opt_outs.insert(address);
+        Ok(())
+    }
+}
+
+// base64url(address).base64url(HMAC-SHA256(address))
+fn token(key: &PKey<Private>, address: &str) -> String {
+    let mut signer = Signer::new(MessageDigest::sha256(), key).expect("HMAC signer");
+    let mac = signer
+        .sign_oneshot_to_vec(address.to_ascii_lowercase().as_bytes())
+        .expect("HMAC signature");
+    format!(
+        "{}.{}",
+        BASE64_URL_SAFE_NO_PAD.encode(address),
+        BASE64_URL_SAFE_NO_PAD.encode(mac)
+    )
+}
+
+fn check_target(target: &str) -> Result<(), String> {
+    let lower = target.to_ascii_lowercase();
+    if !["https://", "http://", "mailto:"]
+        .iter()
+        .any(|scheme| lower.starts_with(scheme))
+    {
+        return Err("list_unsubscribe must be an http(s) URL or a mailto URI".into());
+    }
+    if target
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || "<>\"".contains(c))
+    {
+        return Err("list_unsubscribe contains invalid characters".into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::message::header::Headers;
+
+    fn unsubscribe(file: PathBuf) -> Unsubscribe {
+        Unsubscribe {
+            default_target: Some("mailto:unsubscribe@example.org".into()),
+            base_url: Some("https://mail.example.org".into()),
+            key: Some(PKey::hmac(b"secret").unwrap()),
+            file,
+            opt_outs: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn collect(values: Vec<HeaderValue>) -> Headers {
+        let mut headers = Headers::new();
+        values.into_iter().for_each(|h| headers.insert_raw(h));
+        headers
+    }
+
+    #[test]
+    fn builds_headers_and_verifies_tokens() {
+        let unsubscribe = unsubscribe(PathBuf::from("unused"));
+        let jane: Address = "Jane@example.org".parse().unwrap();
+        let headers = collect(unsubscribe.headers(None, false, &[]).unwrap());
+        assert_eq!(
+            headers.get_raw("List-Unsubscribe"),
+            Some("<mailto:unsubscribe@example.org>")
+        );
+        assert_eq!(headers.get_raw("List-Unsubscribe-Post"), None);
+        let headers = collect(
+            unsubscribe
+                .headers(Some("https://example.org/u?id=1"), false, &[])
+                .unwrap(),
+        );
+        assert_eq!(headers.get_raw("List-Unsubscribe-Post"), None);
+
+        let headers = collect(
+            unsubscribe
+                .headers(
+                    Some("https://example.org/u?id=1"),
+                    true,
+                    std::slice::from_ref(&jane),
+                )
+                .unwrap(),
+        );
+        let value = headers.get_raw("List-Unsubscribe").unwrap();
+        let link = value.split(", ").next().unwrap();
+        assert!(value.ends_with(", <https://example.org/u?id=1>"));
+        assert_eq!(
+            headers.get_raw("List-Unsubscribe-Post"),
+            Some("List-Unsubscribe=One-Click")
+        );
+        let token = link
+            .trim_matches(['<', '>'])
+            .strip_prefix("https://mail.example.org/unsubscribe/")
+            .unwrap();
+        assert_eq!(
+            unsubscribe.verify(token).as_deref(),
+            Some("Jane@example.org")
+        );
+        assert_eq!(unsubscribe.verify(&token.replace('.', ".x")), None);
+        let other = format!(
+            "{}{}",
+            BASE64_URL_SAFE_NO_PAD.encode("evil@example.org"),
+            &token[token.find('.').unwrap()..]
+        );
+        assert_eq!(unsubscribe.verify(&other), None);
+
+        assert!(unsubscribe.headers(None, true, &[]).is_err());
+        assert!(unsubscribe
+            .headers(Some("javascript:alert(1)"), false, &[])
+            .is_err());
+    }
+
+    #[test]
+    fn records_opt_outs() {
+        let dir = env::temp_dir().join(format!("rest2smtp-unsubscribe-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let file = dir.join("data").join("unsubscribed.txt");
+        let unsubscribe = unsubscribe(file.clone());
+        let recipients: Vec<Address> = [
+            "jane@example.org".parse().unwrap(),
+            "bob@example.org".parse().unwrap(),
+        ]
+        .into();
+        assert!(unsubscribe.unsubscribed(&recipients).is_empty());
+
+        unsubscribe.record("JANE@example.org").unwrap();
+        unsubscribe.record("jane@example.org").unwrap();
+        assert_eq!(unsubscribe.unsubscribed(&recipients), ["jane@example.org"]);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "jane@example.org\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}